@@ -1,41 +1,309 @@
+use std::collections::HashMap;
+use std::env;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io;
+use std::net::SocketAddr;
+use std::process::exit;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
 
-use yci;
+use mio_extras::channel::channel;
+use signal_hook::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+use yci::builtin::BuiltinWorker;
+use yci::daemon::*;
+use yci::net::{TCPClientAdapter, TCPWorkerAdapter, WORKER_DEADLINE};
+use yci::obj::*;
 use yci::prog::*;
+use yci::wal::WAL_COMPACT_EVERY;
+use yci::worker::LocalWorker;
 
+static USAGE: &str = "Usage:
+    ycie check <file.ir>
+    ycie run <file.ir> --entry <label>
+    ycie serve --listen <addr> [--api <addr>] [--state <file>] [--worker-deadline <ms>] [--queues <file.json>] [<file.ir> [--entry <label>]]
+";
 
-static TEST_SIMPLE: &str = "./etc/ir/commented_test.ir";
-static TEST_ALGO: &str = "./etc/ir/from_docs.ir";
-static TEST_INCORRECT: &str = "./etc/ir/missing_opcode.ir";
+const EXIT_INVALID: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_STALLED: i32 = 3;
 
-fn main() {
-    let mut file = File::open(TEST_INCORRECT).unwrap();
+struct Args {
+    positional: Vec<String>,
+    entry: Option<String>,
+    listen: Option<String>,
+    api: Option<String>,
+    state: Option<String>,
+    worker_deadline: Option<String>,
+    queues: Option<String>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Args, String> {
+        let mut ret = Args { positional: vec![], entry: None, listen: None, api: None, state: None, worker_deadline: None, queues: None };
+
+        let mut iter = args.iter();
+
+        while let Some(arg) = iter.next() {
+            let target = match arg.as_ref() {
+                "--entry" => &mut ret.entry,
+                "--listen" => &mut ret.listen,
+                "--api" => &mut ret.api,
+                "--state" => &mut ret.state,
+                "--worker-deadline" => &mut ret.worker_deadline,
+                "--queues" => &mut ret.queues,
+                x if x.starts_with("--") => return Err(format!("unknown option `{}`", x)),
+                _ => {
+                    ret.positional.push(arg.clone());
+                    continue;
+                }
+            };
+
+            match iter.next() {
+                Some(val) => *target = Some(val.clone()),
+                None => return Err(format!("option `{}` requires a value", arg)),
+            }
+        }
+
+        Ok(ret)
+    }
+}
+
+fn usage(err: &str) -> ! {
+    eprintln!("error: {}\n\n{}", err, USAGE);
+    exit(EXIT_USAGE)
+}
+
+fn read_file(filename: &str) -> String {
     let mut contents = String::new();
 
-    file.read_to_string(&mut contents);
+    let res = File::open(filename).and_then(|mut file| file.read_to_string(&mut contents));
+
+    if let Err(err) = res {
+        eprintln!("error: could not read `{}`: {}", filename, err);
+        exit(EXIT_INVALID);
+    }
+
+    contents
+}
+
+/// Load the program, printing the diagnostics and exiting if it is invalid.
+fn load(filename: &str) -> Vec<Cmd> {
+    let contents = read_file(filename);
+    let input = ir_input(&contents);
+
+    match ir_load::<u32>(ir_file(input)) {
+        Ok(x) => x,
+        Err(err) => {
+            let formatted = format_error(&input, &err).unwrap_or_else(|_| format!("{:?}", err.code));
+
+            let stderr = io::stderr();
+            let mut handle = stderr.lock();
+
+            writeln!(handle, "{}: line {}: {:?}", filename, err.location.line, err.code).unwrap();
+            writeln!(handle, "{}", formatted).unwrap();
+
+            exit(EXIT_INVALID)
+        }
+    }
+}
+
+fn check(args: &Args) {
+    let filename = match args.positional.as_slice() {
+        [x] => x,
+        _ => usage("`check` expects exactly one file"),
+    };
+
+    let commands = load(filename);
+
+    println!("{}: {} commands", filename, commands.len());
+}
+
+fn run(args: &Args) {
+    let filename = match args.positional.as_slice() {
+        [x] => x,
+        _ => usage("`run` expects exactly one file"),
+    };
+
+    let entry = match &args.entry {
+        Some(x) => x.clone(),
+        None => usage("`run` requires `--entry <label>`"),
+    };
+
+    let commands = load(filename);
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let mut dpu = DPU::default();
+    dpu.get_state_mut().insert_commands(commands.iter());
+
+    let mut worker = LocalWorker::new(BuiltinWorker::default(), tx);
+
+    let (thread_id, exit_rx) = dpu.thread_start(entry, None);
+
+    let res = loop {
+        dpu.wake(epoch_millis());
 
-    dbg!(&contents);
+        let processed = dpu.process(&rx).unwrap_or_else(|err| {
+            eprintln!("error: {}", err);
+            exit(EXIT_INVALID)
+        });
+        let executed = worker.run();
 
-    let contents = ir_input(&contents);
+        if let Ok(res) = exit_rx.try_recv() {
+            break res;
+        }
+
+        if processed == Processed::Requests(0) && executed == 0 {
+            // nothing else can happen until the next sleeping thread wakes up
+            if let Some(deadline) = dpu.next_wake() {
+                sleep(Duration::from_millis(deadline.saturating_sub(epoch_millis())));
+                continue;
+            }
+
+            println!("thread {} stalled: {:?}", thread_id, dpu.thread_status(&thread_id));
+            exit(EXIT_STALLED);
+        }
+    };
+
+    if let Some(ctx) = &res.ctx {
+        println!("{}", serde_json::to_string(ctx).unwrap());
+    }
+
+    match res.result {
+        Ok(_) => println!("thread {} exited", thread_id),
+        Err(err) => {
+            println!("thread {} failed: {:?}", thread_id, err);
+            exit(EXIT_INVALID);
+        }
+    }
+}
 
-    let x = ir_file(contents);
-    let y = ir_load(x);
+fn parse_addr(addr: &str) -> SocketAddr {
+    match addr.parse() {
+        Ok(x) => x,
+        Err(err) => usage(&format!("invalid address `{}`: {}", addr, err)),
+    }
+}
+
+fn serve(args: &Args) {
+    let addr = match &args.listen {
+        Some(x) => parse_addr(x),
+        None => usage("`serve` requires `--listen <addr>`"),
+    };
+
+    let api_addr = args.api.as_ref().map(|x| parse_addr(x));
+
+    let deadline = match &args.worker_deadline {
+        Some(x) => match x.parse::<u64>() {
+            Ok(x) if x > 0 => Duration::from_millis(x),
+            _ => usage("`--worker-deadline` expects a number of milliseconds"),
+        },
+        None => WORKER_DEADLINE,
+    };
+
+    // a `QueueConfig` for each queue, keyed by the command
+    let queues = args.queues.as_ref().map(|filename| {
+        serde_json::from_str::<HashMap<CommandId, QueueConfig>>(&read_file(filename)).unwrap_or_else(|err| {
+            eprintln!("error: could not read the queues from `{}`: {}", filename, err);
+            exit(EXIT_INVALID)
+        })
+    });
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    // every change is logged next to the snapshot, both are recovered from on the next start
+    let mut dpu = match &args.state {
+        Some(path) => {
+            DPU::open(path, WAL_COMPACT_EVERY).unwrap_or_else(|err| {
+                eprintln!("error: could not restore `{}`: {}", path, err);
+                exit(EXIT_INVALID)
+            })
+        }
+        None => DPU::default(),
+    };
 
-    let stdout = io::stderr();
-    let mut handle = stdout.lock();
+    for (queue, config) in queues.unwrap_or_default() {
+        dpu.queue_config(queue, config);
+    }
 
+    match args.positional.as_slice() {
+        [] => {}
+        [filename] => {
+            let commands = load(filename);
+            dpu.get_state_mut().insert_commands(commands.iter());
 
-    match y {
-        Ok(x) => {
-            dbg!(x);
+            if let Some(entry) = &args.entry {
+                let (thread_id, _) = dpu.thread_start(entry.clone(), None);
+                eprintln!("thread {} started at {}", thread_id, entry);
+            }
         }
-        Err(x) => {
-            let err: String = format_error(&contents, &x).unwrap();
-            //err.encode;
+        _ => usage("`serve` expects at most one file"),
+    }
+
+    let _adapter = TCPWorkerAdapter::new(&addr, tx.clone(), deadline).unwrap_or_else(|err| {
+        eprintln!("error: could not listen on {}: {:?}", addr, err);
+        exit(EXIT_INVALID)
+    });
+
+    eprintln!("listening for workers on {}", addr);
+
+    let _api_adapter = api_addr.map(|api_addr| {
+        let adapter = TCPClientAdapter::new(&api_addr, tx.clone()).unwrap_or_else(|err| {
+            eprintln!("error: could not listen on {}: {:?}", api_addr, err);
+            exit(EXIT_INVALID)
+        });
 
-            handle.write(err.as_bytes()).unwrap();
+        eprintln!("listening for clients on {}", api_addr);
+
+        adapter
+    });
+
+    // the daemon stops on the first signal, taking a snapshot before exiting
+    let signals = Signals::new([SIGINT, SIGTERM]).unwrap_or_else(|err| {
+        eprintln!("error: could not handle the signals: {}", err);
+        exit(EXIT_INVALID)
+    });
+
+    let shutdown_tx = tx.clone();
+
+    spawn(move || {
+        if signals.forever().next().is_some() {
+            let _ = shutdown_tx.send(DaemonRequest::Shutdown);
         }
+    });
+
+    if let Err(err) = dpu.run(&rx) {
+        eprintln!("error: {}", err);
+        exit(EXIT_INVALID);
+    }
+
+    if let Err(err) = dpu.compact() {
+        eprintln!("error: could not write the snapshot: {}", err);
+        exit(EXIT_INVALID);
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let (command, rest) = match args.split_first() {
+        Some(x) => x,
+        None => usage("missing command"),
+    };
+
+    let rest = match Args::parse(rest) {
+        Ok(x) => x,
+        Err(err) => usage(&err),
+    };
+
+    match command.as_ref() {
+        "check" => check(&rest),
+        "run" => run(&rest),
+        "serve" => serve(&rest),
+        "help" | "--help" | "-h" => print!("{}", USAGE),
+        x => usage(&format!("unknown command `{}`", x)),
     }
 }
@@ -0,0 +1,321 @@
+use crate::daemon::*;
+use crate::obj::*;
+use crate::worker::*;
+
+/// Opcodes understood by the `BuiltinWorker`.
+pub static BUILTIN_OPCODES: &[&str] = &[
+    "push",
+    "jmp",
+    "set",
+    "null",
+    "cas",
+    "iadd",
+    "icmp",
+    "if",
+    "exit",
+    "call",
+    "ret",
+    "pause",
+    "sleep",
+    "sleep_until",
+    "list_create",
+    "list_length",
+    "list_get",
+    "list_append",
+];
+
+/// A worker implementing the basic control flow and arithmetic opcodes, so that programs
+/// can be executed without any external workers attached.
+///
+/// Every opcode takes the next instruction pointer as its last argument, except for `jmp`, `if`,
+/// `ret` and `exit`. The next instruction pointer of `call` is the one `ret` returns to.
+#[derive(Debug, Clone, Default)]
+pub struct BuiltinWorker {}
+
+fn arg(command: &XCmd, idx: usize) -> Result<&XCmdArg, WorkerErr> {
+    command.args.get(idx).ok_or(
+        WorkerErr::Default(OpErrReason::MissingArg(idx))
+    )
+}
+
+fn arg_value(command: &XCmd, idx: usize) -> Result<ContextValue, WorkerErr> {
+    arg(command, idx)?.value().ok_or(
+        WorkerErr::Default(OpErrReason::InvalidArg(idx))
+    )
+}
+
+fn arg_ident(command: &XCmd, idx: usize) -> Result<XCtxRef, WorkerErr> {
+    arg(command, idx)?.ident().ok_or(
+        WorkerErr::Default(OpErrReason::InvalidArg(idx))
+    )
+}
+
+fn arg_str(command: &XCmd, idx: usize) -> Result<String, WorkerErr> {
+    arg_value(command, idx)?.as_str().map(String::from).ok_or(
+        WorkerErr::Default(OpErrReason::InvalidArg(idx))
+    )
+}
+
+/// Constants in the programs are strings, so these are parsed as well.
+fn arg_int(command: &XCmd, idx: usize) -> Result<i64, WorkerErr> {
+    let val = match arg_value(command, idx)? {
+        ContextValue::Int(x) => Some(x),
+        ContextValue::Str(x) => x.parse::<i64>().ok(),
+        _ => None,
+    };
+
+    val.ok_or(WorkerErr::Default(OpErrReason::InvalidArg(idx)))
+}
+
+fn arg_bool(command: &XCmd, idx: usize) -> Result<bool, WorkerErr> {
+    let val = match arg_value(command, idx)? {
+        ContextValue::Bool(x) => Some(x),
+        ContextValue::Str(x) => x.parse::<bool>().ok(),
+        _ => None,
+    };
+
+    val.ok_or(WorkerErr::Default(OpErrReason::InvalidArg(idx)))
+}
+
+fn arg_list(command: &XCmd, idx: usize) -> Result<Vec<ContextValue>, WorkerErr> {
+    match arg_value(command, idx)? {
+        ContextValue::List(x) => Ok(x),
+        _ => Err(WorkerErr::Default(OpErrReason::InvalidArg(idx))),
+    }
+}
+
+fn jump(ip: ContextValue) -> Op {
+    Op::LocalSet(
+        LOCAL_NIP.into(),
+        RValue::Local(RValueLocal::Const(ip)),
+    )
+}
+
+impl BuiltinWorker {
+    pub fn exec(&self, command: &XCmd) -> WorkerResult {
+        let nip = || arg_value(command, command.args.len().saturating_sub(1));
+
+        match command.opcode.as_ref() {
+            "push" => {
+                Ok(
+                    vec![
+                        Op::LocalSet(
+                            "new_ctx".into(),
+                            RValue::Extern(RValueExtern::ContextCreate),
+                        ),
+                        Op::LocalSet(
+                            LOCAL_CTX.into(),
+                            RValue::Local(RValueLocal::Ref("new_ctx".into())),
+                        ),
+                        jump(nip()?),
+                    ]
+                )
+            }
+            "jmp" => {
+                Ok(vec![jump(arg_value(command, 0)?)])
+            }
+            "set" => {
+                let pairs = command.args.len().saturating_sub(1) / 2;
+
+                let mut ret = Vec::<Op>::with_capacity(pairs + 1);
+
+                for idx in 0..pairs {
+                    let var = arg_ident(command, idx * 2)?;
+                    let val = arg_value(command, idx * 2 + 1)?;
+
+                    ret.push(var.set(RValueLocal::Const(val)));
+                }
+
+                ret.push(jump(nip()?));
+
+                Ok(ret)
+            }
+            "null" => {
+                let var = arg_ident(command, 0)?;
+
+                Ok(
+                    vec![
+                        var.set(RValueLocal::Const(ContextValue::Null)),
+                        jump(nip()?),
+                    ]
+                )
+            }
+            "cas" => {
+                // without the expected value, the variable is expected to be unset
+                let var = arg_ident(command, 0)?;
+
+                let (expected, new) = match command.args.len() {
+                    3 => (None, arg_value(command, 1)?),
+                    _ => (Some(RValueLocal::Const(arg_value(command, 1)?)), arg_value(command, 2)?),
+                };
+
+                Ok(
+                    vec![
+                        var.compare_and_set(expected, RValueLocal::Const(new)),
+                        jump(nip()?),
+                    ]
+                )
+            }
+            "iadd" => {
+                let var = arg_ident(command, 0)?;
+                let a = arg_int(command, 0)?;
+                let b = arg_int(command, 1)?;
+
+                let sum = a.checked_add(b).ok_or(WorkerErr::Default(OpErrReason::InvalidArg(1)))?;
+
+                Ok(
+                    vec![
+                        var.set(RValueLocal::Const(sum.into())),
+                        jump(nip()?),
+                    ]
+                )
+            }
+            "icmp" => {
+                let a = arg_int(command, 0)?;
+                let op = arg_str(command, 1)?;
+                let b = arg_int(command, 2)?;
+                let var = arg_ident(command, 3)?;
+
+                let res = match op.as_ref() {
+                    "<" => a < b,
+                    "<=" => a <= b,
+                    ">" => a > b,
+                    ">=" => a >= b,
+                    "=" => a == b,
+                    "!=" => a != b,
+                    _ => return Err(WorkerErr::Default(OpErrReason::InvalidArg(1)))
+                };
+
+                Ok(
+                    vec![
+                        var.set(RValueLocal::Const(res.into())),
+                        jump(nip()?),
+                    ]
+                )
+            }
+            "if" => {
+                let cond = arg_bool(command, 0)?;
+
+                let target = if cond { 1 } else { 2 };
+
+                Ok(vec![jump(arg_value(command, target)?)])
+            }
+            "exit" => {
+                Ok(vec![Op::ThreadExit])
+            }
+            "call" => {
+                Ok(
+                    vec![
+                        Op::Call(
+                            RValueLocal::Const(arg_value(command, 0)?),
+                            RValueLocal::Const(nip()?),
+                        ),
+                    ]
+                )
+            }
+            "ret" => {
+                Ok(vec![Op::Return])
+            }
+            "pause" => {
+                // the thread is resumed by unpausing the identifier set in the variable
+                let var = arg_ident(command, 0)?;
+
+                Ok(
+                    vec![
+                        Op::LocalSet(
+                            "pause_id".into(),
+                            RValue::Extern(RValueExtern::PauseCreate),
+                        ),
+                        var.set(RValueLocal::Ref("pause_id".into())),
+                        Op::ThreadPause(RValueLocal::Ref("pause_id".into())),
+                        jump(nip()?),
+                    ]
+                )
+            }
+            "sleep" => {
+                let millis = arg_int(command, 0)?;
+
+                Ok(
+                    vec![
+                        Op::Sleep(RValueLocal::Const(millis.into())),
+                        jump(nip()?),
+                    ]
+                )
+            }
+            "sleep_until" => {
+                // milliseconds since the UNIX epoch
+                let deadline = arg_int(command, 0)?;
+
+                Ok(
+                    vec![
+                        Op::ResumeAt(RValueLocal::Const(deadline.into())),
+                        jump(nip()?),
+                    ]
+                )
+            }
+            "list_create" => {
+                let var = arg_ident(command, 0)?;
+
+                Ok(
+                    vec![
+                        var.set(RValueLocal::Const(ContextValue::List(vec![]))),
+                        jump(nip()?),
+                    ]
+                )
+            }
+            "list_length" => {
+                let list = arg_list(command, 0)?;
+                let var = arg_ident(command, 1)?;
+
+                Ok(
+                    vec![
+                        var.set(RValueLocal::Const((list.len() as i64).into())),
+                        jump(nip()?),
+                    ]
+                )
+            }
+            "list_get" => {
+                let list = arg_list(command, 0)?;
+                let idx = arg_int(command, 1)?;
+                let var = arg_ident(command, 2)?;
+
+                let item = list.get(idx as usize).cloned().ok_or(
+                    WorkerErr::Default(OpErrReason::InvalidArg(1))
+                )?;
+
+                Ok(
+                    vec![
+                        var.set(RValueLocal::Const(item)),
+                        jump(nip()?),
+                    ]
+                )
+            }
+            "list_append" => {
+                let var = arg_ident(command, 0)?;
+                let val = arg_value(command, 1)?;
+
+                Ok(
+                    vec![
+                        var.append(RValueLocal::Const(val)),
+                        jump(nip()?),
+                    ]
+                )
+            }
+            _ => Err(WorkerErr::Default(OpErrReason::UnknownOp))
+        }
+    }
+}
+
+impl Worker for BuiltinWorker {
+    fn capacity(&self) -> Option<usize> {
+        None
+    }
+
+    fn queues(&self) -> Vec<CommandId> {
+        BUILTIN_OPCODES.iter().map(|x| x.to_string()).collect()
+    }
+
+    fn put(&mut self, command: &XCmd, mut result_cb: WorkerReplier) {
+        result_cb.reply(self.exec(command))
+    }
+}
@@ -1,15 +1,24 @@
 use rand::prelude::*;
 use serde_derive::{Serialize, Deserialize};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 
 use super::obj::*;
 use super::pubsub::*;
+use super::wal::*;
 use super::worker::*;
 use std::collections::VecDeque;
-use mio_extras::channel::{Sender, Receiver};
+use std::fs::{File, rename};
+use std::io;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use std::sync::mpsc::TryRecvError;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use mio::{Events, Poll, PollOpt, Ready, Token};
+use mio_extras::channel::{Sender, Receiver, channel};
+use mio_extras::timer;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Thread {
     // Identity
     pub(crate) id: ThreadId,
@@ -25,9 +34,19 @@ pub struct Thread {
     // where to jump if exception occurs
     pub(crate) eip: Option<CommandId>,
     // which context to set if exception occurs
+
+    // how many times the current command had timed out, see `QueueTimeout`
+    #[serde(default)]
+    pub(crate) timeouts: usize,
+    // how many times the current command had been retried, see `RetryPolicy`
+    #[serde(default)]
+    pub(crate) retries: usize,
+    // the jobs of the threads with a higher priority are handed out first
+    #[serde(default)]
+    pub(crate) priority: i32,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ThreadError {
     Fetch { id: CommandId },
     Context { id: Option<ContextId> },
@@ -35,9 +54,77 @@ pub enum ThreadError {
 
     WorkerDuring(WorkerErr),
     WorkerPost(OpErr),
+    /// raised from outside of the thread with `DaemonRequest::Raise`
+    Raised(ContextValue),
+    /// the worker did not return the result in time, see `QueueTimeout`
+    Timeout { queue: CommandId, worker: WorkerId },
+}
+
+fn to_value<T: serde::Serialize>(x: &T) -> ContextValue {
+    serde_json::to_value(x).map(ContextValue::from).unwrap_or(ContextValue::Null)
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl ThreadError {
+    /// The error as seen by the exception handler: a map with the name of the variant under
+    /// `kind`, and its fields next to it.
+    pub fn to_value(&self) -> ContextValue {
+        let mut ret = BTreeMap::<String, ContextValue>::new();
+
+        let kind = match self {
+            ThreadError::Fetch { id } => {
+                ret.insert("id".into(), id.clone().into());
+                "Fetch"
+            }
+            ThreadError::Context { id } => {
+                ret.insert("id".into(), id.clone().into());
+                "Context"
+            }
+            ThreadError::Interpolate { err } => {
+                ret.insert("err".into(), to_value(err));
+                "Interpolate"
+            }
+            ThreadError::WorkerDuring(WorkerErr::Custom(fields)) => {
+                let fields = fields.iter().map(|(k, v)| (k.clone(), v.clone().into())).collect();
+                ret.insert("custom".into(), ContextValue::Map(fields));
+                "WorkerDuring"
+            }
+            ThreadError::WorkerDuring(WorkerErr::Default(reason)) => {
+                ret.insert("reason".into(), to_value(reason));
+                "WorkerDuring"
+            }
+            ThreadError::WorkerPost(err) => {
+                ret.insert("op_index".into(), err.op_index.map(|x| ContextValue::Int(x as i64)).unwrap_or(ContextValue::Null));
+                ret.insert("reason".into(), to_value(&err.op_reason));
+                "WorkerPost"
+            }
+            ThreadError::Raised(payload) => {
+                ret.insert("payload".into(), payload.clone());
+                "Raised"
+            }
+            ThreadError::Timeout { queue, worker } => {
+                ret.insert("queue".into(), queue.clone().into());
+                ret.insert("worker".into(), worker.clone().into());
+                "Timeout"
+            }
+        };
+
+        ret.insert("kind".into(), kind.into());
+
+        ContextValue::Map(ret)
+    }
+}
+
+/// Summary of a `ThreadState` that is exposed outside of the daemon.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ThreadStatus {
+    Running(CommandId),
+    Paused(PauseId),
+    /// until the deadline, see `ThreadState::Sleeping`
+    Sleeping(u64),
+    Exited(Result<(), ThreadError>),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub(crate) enum ThreadState {
     Created,
     Fetching(CommandId),
@@ -52,19 +139,82 @@ pub(crate) enum ThreadState {
     Err(ThreadError),
 
     // Waiting
-    // todo On pause, the thread is immediately paused and the other thread is forked
-    // todo that receives the new pause ID
-    // todo this should resolve the situation where the thread hasn't yet entered paused
-    // todo state, but had already received Unpause from whoever is supposed to unpause it.
+    /// until `DaemonRequest::Unpause` is received
     Paused(PauseId),
+    /// until the deadline in milliseconds since the UNIX epoch, see `DPU::wake`
+    Sleeping(u64),
+    /// until the deadline, then the command is queued again, see `RetryPolicy`
+    Delayed(XCmd, u64),
     Exited(Result<(), ThreadError>),
 }
 
+/// The outcome of a thread, sent to its subscribers once it exits.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ThreadExit {
+    pub id: ThreadId,
+    pub result: Result<(), ThreadError>,
+    /// the context of the thread at the moment it had exited
+    pub ctx: Option<Ctx>,
+}
+
+pub(crate) enum ThreadSubscriber {
+    Local(Sender<ThreadExit>),
+    Client(usize, Sender<DaemonClient>),
+}
+
+impl ThreadSubscriber {
+    fn notify(&self, exit: ThreadExit) {
+        // the subscriber might have gone away in the meantime
+        match self {
+            ThreadSubscriber::Local(chan) => {
+                let _ = chan.send(exit);
+            }
+            ThreadSubscriber::Client(idx, chan) => {
+                let _ = chan.send(DaemonClient::Response(*idx, ClientResponse::ThreadExited(exit)));
+            }
+        }
+    }
+}
+
+/// A pause created by `RValueExtern::PauseCreate`, until the thread is unpaused.
+///
+/// The identifier is handed out before the thread pauses, so the unpause may as well arrive
+/// first. Its values are then kept here and the thread carries on as soon as it pauses.
+/// The pauses of a thread are removed once it exits.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Pause {
+    pub(crate) thread: ThreadId,
+    pub(crate) unpaused: Option<HashMap<ContextIdent, ContextValue>>,
+}
+
+/// Records changed since the last `State::take_changes`.
+#[derive(Default)]
+struct Changes {
+    commands: HashSet<CommandId>,
+    contexts: HashSet<ContextId>,
+    threads: HashSet<ThreadId>,
+    pauses: HashSet<PauseId>,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct State {
     commands: HashMap<CommandId, Cmd>,
-    contexts: HashMap<ContextId, Ctx>,
+    pub(crate) contexts: HashMap<ContextId, Ctx>,
     pub(crate) threads: HashMap<ThreadId, Thread>,
-
+    #[serde(default)]
+    pub(crate) pauses: HashMap<PauseId, Pause>,
+    // the subscribers are gone by the time a snapshot is restored
+    #[serde(skip)]
+    subscribers: HashMap<ThreadId, Vec<ThreadSubscriber>>,
+    #[serde(skip)]
+    changes: Changes,
+    /// the deadlines of the sleeping and the delayed threads, rebuilt by `DPU::resume`
+    ///
+    /// the entries are not removed once the thread moves on, but checked against it when due
+    #[serde(skip)]
+    sleeping: BTreeSet<(u64, ThreadId)>,
+
+    #[serde(skip)]
     rng: ThreadRng,
 }
 
@@ -78,19 +228,313 @@ impl State {
     }
 
     pub fn insert_thread(&mut self, thread: Thread) {
+        match &thread.state {
+            ThreadState::Sleeping(deadline) | ThreadState::Delayed(_, deadline) => {
+                self.sleeping.insert((*deadline, thread.id.clone()));
+            }
+            _ => {}
+        }
+
+        self.changes.threads.insert(thread.id.clone());
         self.threads.insert(thread.id.clone(), thread);
     }
 
     pub fn insert_context(&mut self, context: &Ctx) {
+        self.changes.contexts.insert(context.id.clone());
         self.contexts.insert(context.id.clone(), context.clone());
     }
 
+    pub(crate) fn insert_pause(&mut self, id: &PauseId, pause: Pause) {
+        self.changes.pauses.insert(id.clone());
+        self.pauses.insert(id.clone(), pause);
+    }
+
+    pub(crate) fn remove_pause(&mut self, id: &PauseId) {
+        self.changes.pauses.insert(id.clone());
+        self.pauses.remove(id);
+    }
+
     pub fn insert_commands<'a, I>(&mut self, commands: I)
         where I: Iterator<Item=&'a Cmd>, {
         for command in commands {
+            self.changes.commands.insert(command.id.clone());
             self.commands.insert(command.id.clone(), command.clone());
         }
     }
+
+    /// Collect the records changed since the last call into a log entry.
+    pub(crate) fn take_changes(&mut self) -> Option<WalEntry> {
+        let changes = std::mem::take(&mut self.changes);
+
+        if changes.commands.is_empty() && changes.contexts.is_empty() && changes.threads.is_empty()
+            && changes.pauses.is_empty() {
+            return None;
+        }
+
+        Some(
+            WalEntry {
+                commands: changes.commands.iter().filter_map(|x| self.commands.get(x)).cloned().collect(),
+                contexts: changes.contexts.into_iter().map(|x| {
+                    let ctx = self.contexts.get(&x).cloned();
+                    (x, ctx)
+                }).collect(),
+                threads: changes.threads.into_iter().map(|x| {
+                    let thread = self.threads.get(&x).cloned();
+                    (x, thread)
+                }).collect(),
+                pauses: changes.pauses.into_iter().map(|x| {
+                    let pause = self.pauses.get(&x).cloned();
+                    (x, pause)
+                }).collect(),
+            }
+        )
+    }
+
+    pub(crate) fn apply(&mut self, entry: WalEntry) {
+        for command in entry.commands {
+            self.commands.insert(command.id.clone(), command);
+        }
+
+        for (id, ctx) in entry.contexts {
+            match ctx {
+                Some(ctx) => self.contexts.insert(id, ctx),
+                None => self.contexts.remove(&id),
+            };
+        }
+
+        for (id, thread) in entry.threads {
+            match thread {
+                Some(thread) => self.threads.insert(id, thread),
+                None => self.threads.remove(&id),
+            };
+        }
+
+        for (id, pause) in entry.pauses {
+            match pause {
+                Some(pause) => self.pauses.insert(id, pause),
+                None => self.pauses.remove(&id),
+            };
+        }
+    }
+
+    /// Write the snapshot into a temporary file first, so that a crash never leaves a partial one behind.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, self)?;
+
+        let file = writer.into_inner().map_err(|x| x.into_error())?;
+        file.sync_all()?;
+
+        rename(tmp_path, path)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<State> {
+        let reader = BufReader::new(File::open(path)?);
+
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    fn thread_exit(&self, thread: &Thread) -> Option<ThreadExit> {
+        match &thread.state {
+            ThreadState::Exited(result) => Some(
+                ThreadExit {
+                    id: thread.id.clone(),
+                    result: result.clone(),
+                    ctx: thread.ctx.as_ref().and_then(|x| self.contexts.get(x)).cloned(),
+                }
+            ),
+            _ => None
+        }
+    }
+
+    /// Notify the subscriber once the thread exits, or immediately if it already had.
+    ///
+    /// Returns false if the thread does not exist.
+    pub(crate) fn thread_subscribe(&mut self, id: &ThreadId, subscriber: ThreadSubscriber) -> bool {
+        let thread = match self.threads.get(id) {
+            Some(x) => x,
+            None => return false,
+        };
+
+        match self.thread_exit(thread) {
+            Some(exit) => subscriber.notify(exit),
+            None => self.subscribers.entry(id.clone()).or_default().push(subscriber),
+        }
+
+        true
+    }
+
+    fn thread_exited(&mut self, thread: &Thread) {
+        let exit = match self.thread_exit(thread) {
+            Some(x) => x,
+            None => return,
+        };
+
+        // nothing can unpause the thread anymore
+        self.remove_thread_pauses(&thread.id);
+
+        for subscriber in self.subscribers.remove(&thread.id).unwrap_or_default() {
+            subscriber.notify(exit.clone());
+        }
+    }
+
+    /// Remove the pauses created by the thread, including the unpauses buffered for them.
+    fn remove_thread_pauses(&mut self, id: &ThreadId) {
+        let pause_ids: Vec<PauseId> = self.pauses.iter()
+            .filter(|(_, pause)| &pause.thread == id)
+            .map(|(pause_id, _)| pause_id.clone())
+            .collect();
+
+        for pause_id in pause_ids {
+            self.remove_pause(&pause_id);
+        }
+    }
+}
+
+/// The changes made by a batch of ops, staged on top of the `State` until every op succeeds.
+pub struct Transaction<'a> {
+    state: &'a mut State,
+    /// `None` if the record had been removed
+    contexts: HashMap<ContextId, Option<Ctx>>,
+    threads: HashMap<ThreadId, Option<Thread>>,
+    pauses: HashMap<PauseId, Option<Pause>>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(state: &'a mut State) -> Self {
+        Transaction {
+            state,
+            contexts: HashMap::default(),
+            threads: HashMap::default(),
+            pauses: HashMap::default(),
+        }
+    }
+
+    pub(crate) fn create_id(&mut self) -> GenId {
+        self.state.create_id()
+    }
+
+    pub(crate) fn context(&self, id: &ContextId) -> Option<&Ctx> {
+        match self.contexts.get(id) {
+            Some(x) => x.as_ref(),
+            None => self.state.contexts.get(id),
+        }
+    }
+
+    pub(crate) fn context_mut(&mut self, id: &ContextId) -> Option<&mut Ctx> {
+        if !self.contexts.contains_key(id) {
+            let ctx = self.state.contexts.get(id)?.clone();
+            self.contexts.insert(id.clone(), Some(ctx));
+        }
+
+        self.contexts.get_mut(id).and_then(|x| x.as_mut())
+    }
+
+    pub(crate) fn context_insert(&mut self, ctx: Ctx) {
+        self.contexts.insert(ctx.id.clone(), Some(ctx));
+    }
+
+    pub(crate) fn context_remove(&mut self, id: &ContextId) -> bool {
+        let exists = match self.contexts.get(id) {
+            Some(x) => x.is_some(),
+            None => self.state.contexts.contains_key(id),
+        };
+
+        if exists {
+            self.contexts.insert(id.clone(), None);
+        }
+
+        exists
+    }
+
+    pub(crate) fn thread_insert(&mut self, thread: Thread) {
+        self.threads.insert(thread.id.clone(), Some(thread));
+    }
+
+    pub(crate) fn thread_remove(&mut self, id: &ThreadId) -> bool {
+        let exists = match self.threads.get(id) {
+            Some(x) => x.is_some(),
+            None => self.state.threads.contains_key(id),
+        };
+
+        if exists {
+            self.threads.insert(id.clone(), None);
+        }
+
+        exists
+    }
+
+    pub(crate) fn pause(&self, id: &PauseId) -> Option<&Pause> {
+        match self.pauses.get(id) {
+            Some(x) => x.as_ref(),
+            None => self.state.pauses.get(id),
+        }
+    }
+
+    pub(crate) fn pause_insert(&mut self, id: PauseId, pause: Pause) {
+        self.pauses.insert(id, Some(pause));
+    }
+
+    pub(crate) fn pause_remove(&mut self, id: &PauseId) {
+        self.pauses.insert(id.clone(), None);
+    }
+
+    /// Set the values in the context of the thread, creating one if it has none.
+    pub(crate) fn thread_context_extend(&mut self, thread: &mut Thread, vals: HashMap<ContextIdent, ContextValue>) {
+        if vals.is_empty() {
+            return;
+        }
+
+        match thread.ctx.as_ref().and_then(|x| self.context_mut(x)) {
+            Some(ctx) => ctx.vals.extend(vals),
+            None => {
+                let id: ContextId = self.create_id().to_string();
+                self.context_insert(Ctx::create(id.clone(), vals));
+                thread.ctx = Some(id);
+            }
+        }
+    }
+
+    /// Apply the changes, returning the threads that were removed.
+    pub(crate) fn commit(self) -> Vec<Thread> {
+        let state = self.state;
+        let mut removed = vec![];
+
+        for (id, pause) in self.pauses {
+            match pause {
+                Some(pause) => state.insert_pause(&id, pause),
+                None => state.remove_pause(&id),
+            }
+        }
+
+        for (id, ctx) in self.contexts {
+            match ctx {
+                Some(ctx) => state.insert_context(&ctx),
+                None => {
+                    state.changes.contexts.insert(id.clone());
+                    state.contexts.remove(&id);
+                }
+            }
+        }
+
+        for (id, thread) in self.threads {
+            match thread {
+                Some(thread) => state.insert_thread(thread),
+                None => {
+                    state.changes.threads.insert(id.clone());
+                    removed.extend(state.threads.remove(&id));
+                    state.remove_thread_pauses(&id);
+                    // the subscribers see the channel disconnect
+                    state.subscribers.remove(&id);
+                }
+            }
+        }
+
+        removed
+    }
 }
 
 impl Default for State {
@@ -99,17 +543,87 @@ impl Default for State {
             commands: HashMap::<CommandId, Cmd>::default(),
             contexts: HashMap::<ContextId, Ctx>::default(),
             threads: HashMap::<ThreadId, Thread>::default(),
+            pauses: HashMap::<PauseId, Pause>::default(),
+            subscribers: HashMap::<ThreadId, Vec<ThreadSubscriber>>::default(),
+            changes: Changes::default(),
+            sleeping: BTreeSet::<(u64, ThreadId)>::default(),
             rng: ThreadRng::default(),
         }
     }
 }
 
+/// How long the workers may take on the jobs of a queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueTimeout {
+    /// milliseconds since the job was assigned
+    pub after: u64,
+    /// how many times the job is put back on the queue before the step fails with `ThreadError::Timeout`
+    pub requeue: usize,
+}
+
+/// How the failed results of the jobs of a queue are retried before the exception handler runs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// the most times the job is run, the first one included
+    pub attempts: usize,
+    /// milliseconds before the first retry, doubled with every retry after it
+    pub backoff: u64,
+    /// the `code` fields of the `WorkerErr::Custom` errors that are retried, `None` retrying all of them
+    pub codes: Option<HashSet<String>>,
+}
+
+impl RetryPolicy {
+    /// The milliseconds to wait before retrying the job, or `None` if it is not retried.
+    ///
+    /// `WorkerErr::Default` is never retried, as the worker would fail the same way again.
+    pub fn delay(&self, err: &WorkerErr, retries: usize) -> Option<u64> {
+        let retryable = match (err, &self.codes) {
+            (WorkerErr::Custom(_), None) => true,
+            (WorkerErr::Custom(fields), Some(codes)) => fields.get("code").map_or(false, |x| codes.contains(x)),
+            (WorkerErr::Default(_), _) => false,
+        };
+
+        if !retryable || retries.saturating_add(1) >= self.attempts {
+            return None;
+        }
+
+        let factor = 1u64.checked_shl(retries as u32).unwrap_or(u64::MAX);
+
+        Some(self.backoff.saturating_mul(factor))
+    }
+}
+
+/// The settings of a queue, as read by `ycie serve --queues <file.json>`.
+///
+/// The settings left out keep their defaults.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QueueConfig {
+    pub timeout: Option<QueueTimeout>,
+    pub retry: Option<RetryPolicy>,
+    pub limit: Option<QueueLimit>,
+    pub weight: Option<QueueWeight>,
+    pub selection: Option<Selection>,
+}
+
+/// The timeouts and the retry policies of the queues, and the deadlines of the jobs assigned on them.
+#[derive(Default)]
+pub(crate) struct QueuePolicies {
+    timeouts: HashMap<CommandId, QueueTimeout>,
+    retries: HashMap<CommandId, RetryPolicy>,
+    /// the entries are not removed once the result arrives, but checked against the thread when due
+    assigned: BTreeSet<(u64, ThreadId, StepId)>,
+}
+
 pub struct DPU {
     state: State,
 
     multi_queue: MQ,
     workers: WS,
     assignment_queue: VecDeque<Ass>,
+    policies: QueuePolicies,
+
+    wal: Option<Wal>,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
@@ -131,39 +645,96 @@ impl RValueLocal {
             }
         }
     }
+
+    /// Resolve the identifier of a context, a thread or a variable, which can not be null.
+    pub fn resolve_id(
+        &self,
+        locals: &HashMap<ContextIdent, ContextValue>,
+    ) -> Result<GenId, OpErrReason> {
+        match self.resolve(locals)? {
+            ContextValue::Str(x) => Ok(x),
+            ContextValue::Null => Err(OpErrReason::IdNull),
+            x => Err(OpErrReason::IdInvalid(x)),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum RValueExtern {
     ContextCreate,
+    /// a new context holding the values of an existing one
+    ContextCopy(RValueLocal),
     ThreadCreate(RValueLocal, Option<RValueLocal>),
+    /// a new `PauseId` of the current thread, see `Op::ThreadPause`
+    PauseCreate,
+    /// the item of the list held by the context variable at the index
+    ListGet(RValueLocal, RValueLocal, RValueLocal),
 }
 
 impl RValueExtern {
     pub fn resolve(
         &self,
         locals: &HashMap<ContextIdent, ContextValue>,
-        state: &mut State,
+        tx: &mut Transaction,
     ) -> Result<ContextValue, OpErrReason> {
         match self {
             RValueExtern::ContextCreate => {
-                let id: ContextId = state.create_id().to_string();
-                state.insert_context(&Ctx::empty(id.clone()));
+                let id: ContextId = tx.create_id().to_string();
+                tx.context_insert(Ctx::empty(id.clone()));
+                Ok(ContextValue::from(id))
+            }
+            RValueExtern::ContextCopy(src) => {
+                let src = src.resolve_id(locals)?;
+
+                let vals = match tx.context(&src) {
+                    Some(x) => x.vals.clone(),
+                    None => return Err(OpErrReason::ContextCopyInvalid { src }),
+                };
+
+                let id: ContextId = tx.create_id().to_string();
+                tx.context_insert(Ctx::create(id.clone(), vals));
+                Ok(ContextValue::from(id))
+            }
+            RValueExtern::PauseCreate => {
+                let thread = RValueLocal::Ref(LOCAL_TID.into()).resolve_id(locals)?;
+
+                let id: PauseId = tx.create_id().to_string();
+                tx.pause_insert(id.clone(), Pause { thread, unpaused: None });
+
                 Ok(ContextValue::from(id))
             }
             RValueExtern::ThreadCreate(ip, ctx) => {
-                let ip = ip.resolve(locals)?;
+                let ip = ip.resolve_id(locals)?;
 
                 let ctx: Option<String> = match ctx {
-                    Some(ctx) => Some(ctx.resolve(locals)?),
+                    Some(ctx) => ctx.resolve(locals)?.as_str().map(String::from),
                     None => None
                 };
 
-                let id: ThreadId = state.create_id().to_string();
-                state.insert_thread(Thread::create(id.clone(), ip, ctx));
+                let id: ThreadId = tx.create_id().to_string();
+                tx.thread_insert(Thread::create(id.clone(), ip, ctx));
 
                 Ok(ContextValue::from(id))
             }
+            RValueExtern::ListGet(ctx_ident, ctx_val_ident, index) => {
+                let ctx_ident = ctx_ident.resolve_id(locals)?;
+                let ctx_val_ident = ctx_val_ident.resolve_id(locals)?;
+                let index = index.resolve(locals)?;
+
+                let context = match tx.context(&ctx_ident) {
+                    Some(x) => x,
+                    None => return Err(OpErrReason::ContextRefInvalid { ident: ctx_ident }),
+                };
+
+                let list = match context.vals.get(&ctx_val_ident).and_then(|x| x.as_list()) {
+                    Some(x) => x,
+                    None => return Err(OpErrReason::ListInvalid { id: ctx_ident, ident: ctx_val_ident }),
+                };
+
+                let item = index.as_int().and_then(|x| if x < 0 { None } else { list.get(x as usize) });
+
+                item.cloned().ok_or(OpErrReason::ListIndexInvalid { index })
+            }
         }
     }
 }
@@ -178,11 +749,11 @@ impl RValue {
     pub fn resolve(
         &self,
         locals: &HashMap<ContextIdent, ContextValue>,
-        state: &mut State,
+        tx: &mut Transaction,
     ) -> Result<ContextValue, OpErrReason> {
         match self {
             RValue::Local(x) => x.resolve(locals),
-            RValue::Extern(x) => x.resolve(locals, state),
+            RValue::Extern(x) => x.resolve(locals, tx),
         }
     }
 }
@@ -191,12 +762,35 @@ impl RValue {
 pub enum Op {
     LocalSet(ContextIdent, RValue),
 
+    /// setting `ContextValue::Null` keeps the variable, as opposed to `ContextUnset`
     ContextSet(RValueLocal, RValueLocal, RValueLocal),
-    // todo ContextCompareAndSet (allows for writing mutexes easier)
-    //ContextCopy(RValueLocal, RValueLocal, RValueLocal),
+    /// set the value only if the current one is the expected one, `None` expecting it to be unset
+    ///
+    /// fails the whole result with `OpErrReason::CompareFailed` otherwise
+    ContextCompareAndSet(RValueLocal, RValueLocal, Option<RValueLocal>, RValueLocal),
+    /// set every key of the context
+    ContextSetMany(RValueLocal, Vec<(RValueLocal, RValueLocal)>),
+    /// remove a single key of the context
+    ContextUnset(RValueLocal, RValueLocal),
+    /// copy every value of the first context into the second one
+    ContextMerge(RValueLocal, RValueLocal),
+    /// append the value to the list held by the context variable
+    ListAppend(RValueLocal, RValueLocal, RValueLocal),
+    /// jump to the first ip in a new context, linked to the current one and the second ip
+    Call(RValueLocal, RValueLocal),
+    /// remove the context created by `Call`, going back to the context and the ip it is linked to
+    Return,
     ContextRemove(RValueLocal),
 
     ThreadRemove(RValueLocal),
+    /// pause the thread once every op of the result is applied, until it is unpaused
+    ThreadPause(RValueLocal),
+    /// put the thread to sleep for the number of milliseconds once every op of the result is applied
+    Sleep(RValueLocal),
+    /// put the thread to sleep until the milliseconds since the UNIX epoch, see `Op::Sleep`
+    ResumeAt(RValueLocal),
+    /// end the thread successfully once every op of the result is applied
+    ThreadExit,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
@@ -204,19 +798,38 @@ pub enum OpErrReason {
     ContextDoesNotExist { id: ContextId },
     ThreadDoesNotExist { id: ThreadId },
     LocalRefInvalid { ident: ContextIdent },
-    ContextRefInvalid { ident: ContextValue },
+    ContextRefInvalid { ident: ContextId },
     InvalidArg(usize),
     MissingArg(usize),
-    ThreadRefInvalid { ident: ContextValue },
+    ThreadRefInvalid { ident: ThreadId },
     CommandRefInvalid(Option<ContextValue>),
     PostStepped { current: StepId, selected: StepId },
+    CompareFailed { ident: ContextIdent, expected: Option<ContextValue>, found: Option<ContextValue> },
+    ContextCopyInvalid { src: ContextId },
+    ContextSetManyInvalid { id: ContextId },
+    ContextKeyDoesNotExist { id: ContextId, ident: ContextIdent },
+    /// either of the contexts does not exist
+    ContextMergeInvalid { id: ContextId },
     UnknownOp,
+    /// a null value was used as an identifier
+    IdNull,
+    /// a value other than a string was used as an identifier
+    IdInvalid(ContextValue),
+    /// the variable is not set to a list
+    ListInvalid { id: ContextId, ident: ContextIdent },
+    ListIndexInvalid { index: ContextValue },
+    /// the context had not been created by `Call`
+    FrameInvalid { id: ContextId },
+    /// the pause does not exist, or belongs to another thread
+    PauseInvalid { id: PauseId },
+    /// the value is not a non-negative integer
+    DeadlineInvalid(ContextValue),
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct OpErr {
-    op_index: Option<usize>,
-    op_reason: OpErrReason,
+    pub(crate) op_index: Option<usize>,
+    pub(crate) op_reason: OpErrReason,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
@@ -237,6 +850,26 @@ impl Thread {
             ctx: ctx,
             state: ThreadState::Created,
             eip: None,
+            timeouts: 0,
+            retries: 0,
+            priority: 0,
+        }
+    }
+
+    /// Whether the thread waits for a worker to return the result of the current step.
+    pub(crate) fn is_waiting(&self) -> bool {
+        match &self.state {
+            ThreadState::Queued(_) | ThreadState::Assigned(_, _) => true,
+            _ => false,
+        }
+    }
+
+    pub fn status(&self) -> ThreadStatus {
+        match &self.state {
+            ThreadState::Paused(pause_id) => ThreadStatus::Paused(pause_id.clone()),
+            ThreadState::Sleeping(deadline) => ThreadStatus::Sleeping(*deadline),
+            ThreadState::Exited(res) => ThreadStatus::Exited(res.clone()),
+            _ => ThreadStatus::Running(self.ip.clone()),
         }
     }
 }
@@ -249,6 +882,9 @@ impl Default for DPU {
             workers: WS::default(),
 
             assignment_queue: VecDeque::<Ass>::default(),
+            policies: QueuePolicies::default(),
+
+            wal: None,
         }
     }
 }
@@ -260,10 +896,12 @@ pub static LOCAL_EIP: &str = "$eip";
 pub static LOCAL_CTX: &str = "$ctx";
 pub static LOCAL_PAR_CTX: &str = "^ctx";
 pub static LOCAL_PAR_IP: &str = "^ip";
+/// the error caught by the exception handler, see `ThreadError::to_value`
+pub static LOCAL_EXC: &str = "^exc";
 
-pub(crate) type MQ = MultiQueue<WorkerId, ContextValue, (ThreadId, StepId)>;
+pub(crate) type MQ = MultiQueue<WorkerId, CommandId, (ThreadId, StepId)>;
 pub(crate) type WS = HashMap<WorkerId, DaemonWorkerInfo>;
-pub(crate) type Ass = Assignment<WorkerId, ContextValue, (ThreadId, StepId)>;
+pub(crate) type Ass = Assignment<WorkerId, CommandId, (ThreadId, StepId)>;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct WorkerInfo (
@@ -284,12 +922,73 @@ pub enum DaemonWorker {
     JobAssigned(ThreadId, StepId, CommandId, XCmd),
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ClientRequest {
+    /// commands as loaded by `ir_load`
+    CommandsAdd(Vec<Cmd>),
+    ContextCreate(HashMap<ContextIdent, ContextValue>),
+    ThreadCreate(CommandId, Option<ContextId>),
+    ThreadStatus(ThreadId),
+    /// answered with `ThreadExited` once the thread exits
+    ThreadWait(ThreadId),
+    /// answered with the status of the thread after the exception is raised in it
+    ThreadRaise(ThreadId, ContextValue),
+    ThreadUnpause(PauseId, HashMap<ContextIdent, ContextValue>),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ClientResponse {
+    CommandsAdded(usize),
+    ContextCreated(ContextId),
+    ThreadCreated(ThreadId),
+    ThreadStatus(ThreadId, Option<ThreadStatus>),
+    ThreadExited(ThreadExit),
+    /// `false` if the pause does not exist, or it was unpaused already
+    ThreadUnpaused(PauseId, bool),
+}
+
+#[derive(Clone, Debug)]
+pub enum DaemonClient {
+    Response(usize, ClientResponse),
+}
+
 pub enum DaemonRequest {
     Finished(WorkerId, ThreadId, StepId, CommandId, WorkerResult),
 
+    /// a request from a client, the response is sent back with the same index
+    Client(usize, ClientRequest, Sender<DaemonClient>),
+
     /// worker needs a WorkerId
     WorkerAdd(WorkerInfo, Sender<DaemonWorker>),
     WorkerRemove(WorkerId),
+
+    /// raise `ThreadError::Raised` in the thread, whatever it is waiting on
+    Raise(ThreadId, ContextValue),
+    /// resume the paused thread, setting the values in its context
+    Unpause(PauseId, HashMap<ContextIdent, ContextValue>),
+
+    /// stop the daemon loop once the preceding requests are processed
+    Shutdown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Processed {
+    Requests(usize),
+    /// either `DaemonRequest::Shutdown` was received, or every sender had been dropped
+    Shutdown,
+}
+
+const DAEMON_REQUEST: Token = Token(0);
+const DAEMON_TIMER: Token = Token(1);
+
+/// The resolution of the timer waking the sleeping threads up in `DPU::run`.
+const TIMER_TICK: Duration = Duration::from_millis(10);
+
+/// The current time in milliseconds since the UNIX epoch, as used by the deadlines of `Op::Sleep`.
+pub fn epoch_millis() -> u64 {
+    let since = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    since.as_secs() * 1000 + u64::from(since.subsec_millis())
 }
 
 impl DPU {
@@ -297,6 +996,345 @@ impl DPU {
         &mut self.state
     }
 
+    pub fn snapshot<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.state.save(path)
+    }
+
+    /// Create a daemon from a snapshot taken by `snapshot`.
+    ///
+    /// The workers that had the jobs assigned are gone, so the threads that were queued or
+    /// assigned are interpolated again and requeued.
+    pub fn restore<P: AsRef<Path>>(path: P) -> io::Result<DPU> {
+        Ok(DPU::resume(State::load(path)?))
+    }
+
+    /// Create a daemon that writes every change into the log next to the snapshot at `path`,
+    /// recovering from the snapshot and the log if they exist.
+    ///
+    /// The log is compacted into the snapshot once it has `compact_every` entries.
+    pub fn open<P: AsRef<Path>>(path: P, compact_every: usize) -> io::Result<DPU> {
+        let (state, wal) = Wal::open(path, compact_every)?;
+
+        let mut dpu = DPU::resume(state);
+        dpu.wal = Some(wal);
+
+        DPU::commit(&mut dpu.wal, &mut dpu.state);
+        dpu.failure()?;
+
+        Ok(dpu)
+    }
+
+    /// Take a snapshot and truncate the log, if the daemon has one.
+    pub fn compact(&mut self) -> io::Result<()> {
+        match &mut self.wal {
+            Some(wal) => wal.compact(&self.state),
+            None => Ok(()),
+        }
+    }
+
+    fn resume(state: State) -> DPU {
+        let mut dpu = DPU {
+            state,
+            ..DPU::default()
+        };
+
+        let requeued: Vec<ThreadId> = dpu.state.threads.values_mut().filter_map(
+            |thread| match &thread.state {
+                ThreadState::Queued(cmd) | ThreadState::Assigned(cmd, _) => {
+                    thread.state = ThreadState::Interpolated(cmd.clone());
+                    Some(thread.id.clone())
+                }
+                _ => None
+            }
+        ).collect();
+
+        // the deadlines are kept, the ones passed in the meantime are due on the first `wake`
+        dpu.state.sleeping = dpu.state.threads.values().filter_map(
+            |thread| match &thread.state {
+                ThreadState::Sleeping(deadline) | ThreadState::Delayed(_, deadline) => {
+                    Some((*deadline, thread.id.clone()))
+                }
+                _ => None
+            }
+        ).collect();
+
+        for thread_id in requeued {
+            DPU::proceed(
+                &thread_id,
+                &mut dpu.state,
+                &mut dpu.assignment_queue,
+                &mut dpu.multi_queue,
+            );
+        }
+
+        dpu
+    }
+
+    /// Start a new thread at `ep`, returning its identifier and a channel that receives its exit.
+    pub fn thread_start(&mut self, ep: CommandId, ctx: Option<ContextId>) -> (ThreadId, Receiver<ThreadExit>) {
+        let ret = DPU::job_add(
+            ep,
+            ctx,
+            &mut self.state,
+            &mut self.assignment_queue,
+            &mut self.multi_queue,
+        );
+
+        DPU::commit(&mut self.wal, &mut self.state);
+
+        ret
+    }
+
+    /// See `DaemonRequest::Raise`, `false` if the thread does not exist or had already exited.
+    pub fn thread_raise(&mut self, id: &ThreadId, payload: ContextValue) -> bool {
+        let ret = DPU::raise(
+            id,
+            payload,
+            &mut self.state,
+            &mut self.assignment_queue,
+            &mut self.multi_queue,
+        );
+
+        DPU::commit(&mut self.wal, &mut self.state);
+
+        ret
+    }
+
+    /// See `DaemonRequest::Unpause`, `false` if the pause does not exist or was unpaused already.
+    pub fn thread_unpause(&mut self, id: &PauseId, vals: HashMap<ContextIdent, ContextValue>) -> bool {
+        let ret = DPU::unpause(
+            id,
+            vals,
+            &mut self.state,
+            &mut self.assignment_queue,
+            &mut self.multi_queue,
+        );
+
+        DPU::commit(&mut self.wal, &mut self.state);
+
+        ret
+    }
+
+    /// Resume the threads sleeping until `now` or earlier, and queue the delayed retries that are
+    /// due, returning how many threads were resumed.
+    pub fn wake(&mut self, now: u64) -> usize {
+        let ret = DPU::wake_until(
+            now,
+            &mut self.state,
+            &mut self.assignment_queue,
+            &mut self.multi_queue,
+        );
+
+        DPU::commit(&mut self.wal, &mut self.state);
+
+        ret
+    }
+
+    /// The earliest deadline of the sleeping threads, if any.
+    pub fn next_wake(&self) -> Option<u64> {
+        self.state.sleeping.iter().next().map(|(deadline, _)| *deadline)
+    }
+
+    /// Set the timeout of the jobs assigned on the queue from now on, `None` waiting for them forever.
+    pub fn queue_timeout(&mut self, queue: CommandId, timeout: Option<QueueTimeout>) {
+        match timeout {
+            Some(x) => self.policies.timeouts.insert(queue, x),
+            None => self.policies.timeouts.remove(&queue),
+        };
+    }
+
+    /// Set the retry policy of the failed results on the queue, `None` failing the step right away.
+    pub fn queue_retry(&mut self, queue: CommandId, policy: Option<RetryPolicy>) {
+        match policy {
+            Some(x) => self.policies.retries.insert(queue, x),
+            None => self.policies.retries.remove(&queue),
+        };
+    }
+
+    /// Apply the settings of the queue, see `QueueConfig`.
+    pub fn queue_config(&mut self, queue: CommandId, config: QueueConfig) {
+        self.queue_timeout(queue.clone(), config.timeout);
+        self.queue_retry(queue.clone(), config.retry);
+
+        if let Some(limit) = config.limit {
+            self.queue_limit(queue.clone(), limit);
+        }
+
+        if let Some(weight) = config.weight {
+            self.queue_weight(queue.clone(), weight);
+        }
+
+        if let Some(selection) = config.selection {
+            self.queue_selection(queue, selection);
+        }
+    }
+
+    /// Set the priority and the weight of the queue, see `QueueWeight`.
+    pub fn queue_weight(&mut self, queue: CommandId, weight: QueueWeight) {
+        self.multi_queue.queue_weight(queue, weight);
+    }
+
+    /// Set the limits of the queue across all of its workers, see `QueueLimit`.
+    pub fn queue_limit(&mut self, queue: CommandId, limit: QueueLimit) {
+        self.multi_queue.queue_limit(queue, limit);
+    }
+
+    /// Assign the jobs held back by the rate limits of the queues as of `now`, returning how many
+    /// of them were assigned.
+    ///
+    /// The jobs are handed out to the workers with the next `DPU::process`.
+    pub fn release(&mut self, now: u64) -> usize {
+        let released = self.multi_queue.tick(now);
+        let ret = released.len();
+
+        self.assignment_queue.extend(released);
+
+        ret
+    }
+
+    /// When a job held back by a rate limit can be assigned next, if any.
+    pub fn next_release(&self) -> Option<u64> {
+        self.multi_queue.next_release()
+    }
+
+    /// Set how the workers of the queue are picked, see `Selection`.
+    pub fn queue_selection(&mut self, queue: CommandId, selection: Selection) {
+        self.multi_queue.queue_selection(queue, selection);
+    }
+
+    /// Cancel the jobs assigned until `now` or earlier that are past their `QueueTimeout`,
+    /// returning how many of them were cancelled.
+    pub fn expire(&mut self, now: u64) -> usize {
+        let ret = DPU::expire_until(
+            now,
+            &mut self.state,
+            &mut self.assignment_queue,
+            &mut self.multi_queue,
+            &mut self.policies,
+        );
+
+        DPU::commit(&mut self.wal, &mut self.state);
+
+        ret
+    }
+
+    /// The earliest deadline of the assigned jobs, if any.
+    pub fn next_expiry(&self) -> Option<u64> {
+        self.policies.assigned.iter().next().map(|(deadline, _, _)| *deadline)
+    }
+
+    /// Set the priority of the jobs the thread creates from now on, `false` if it does not exist.
+    pub fn thread_priority(&mut self, id: &ThreadId, priority: i32) -> bool {
+        let mut thread = match self.state.threads.get(id) {
+            Some(x) => x.clone(),
+            None => return false,
+        };
+
+        thread.priority = priority;
+        self.state.insert_thread(thread);
+
+        DPU::commit(&mut self.wal, &mut self.state);
+
+        true
+    }
+
+    pub fn thread_status(&self, id: &ThreadId) -> Option<ThreadStatus> {
+        self.state.threads.get(id).map(|x| x.status())
+    }
+
+    /// Subscribe to the exit of an existing thread.
+    pub fn thread_subscribe(&mut self, id: &ThreadId) -> Option<Receiver<ThreadExit>> {
+        let (tx, rx) = channel::<ThreadExit>();
+
+        if self.state.thread_subscribe(id, ThreadSubscriber::Local(tx)) {
+            Some(rx)
+        } else {
+            None
+        }
+    }
+
+    pub fn context(&self, id: &ContextId) -> Option<&Ctx> {
+        self.state.contexts.get(id)
+    }
+
+    /// Process every request currently in the channel and hand out the resulting assignments.
+    ///
+    /// Fails if the changes could not be written into the log, nothing is processed from then on.
+    pub fn process(&mut self, receiver: &Receiver<DaemonRequest>) -> io::Result<Processed> {
+        self.failure()?;
+
+        let processed = DPU::process_channel(
+            receiver,
+            &mut self.wal,
+            &mut self.state,
+            &mut self.assignment_queue,
+            &mut self.workers,
+            &mut self.multi_queue,
+            &mut self.policies,
+        );
+
+        // the workers are not handed jobs whose assignment was not made durable
+        self.failure()?;
+
+        DPU::process_assignments(
+            &mut self.state,
+            &mut self.assignment_queue,
+            &mut self.workers,
+            &mut self.policies,
+        );
+
+        Ok(processed)
+    }
+
+    /// Run the daemon until it is asked to shut down.
+    pub fn run(&mut self, receiver: &Receiver<DaemonRequest>) -> io::Result<()> {
+        let poll = Poll::new()?;
+
+        let mut timer = timer::Builder::default().tick_duration(TIMER_TICK).build::<u64>();
+
+        poll.register(receiver, DAEMON_REQUEST, Ready::readable(), PollOpt::edge())?;
+        poll.register(&timer, DAEMON_TIMER, Ready::readable(), PollOpt::edge())?;
+
+        let mut events = Events::with_capacity(1024);
+
+        // only the earliest deadline is set on the timer
+        let mut armed: Option<(u64, timer::Timeout)> = None;
+
+        loop {
+            // the timer may go off a tick early, in which case it is set again
+            while timer.poll().is_some() {
+                armed = None;
+            }
+
+            let now = epoch_millis();
+
+            self.wake(now);
+            self.expire(now);
+            self.release(now);
+
+            // the registration is edge-triggered, so everything pending must be drained before waiting
+            if let Processed::Shutdown = self.process(receiver)? {
+                return Ok(());
+            }
+
+            let next = vec![self.next_wake(), self.next_expiry(), self.next_release()].into_iter().flatten().min();
+
+            if armed.as_ref().map(|(deadline, _)| *deadline) != next {
+                if let Some((_, timeout)) = armed.take() {
+                    timer.cancel_timeout(&timeout);
+                }
+
+                armed = next.map(|deadline| {
+                    let delay = Duration::from_millis(deadline.saturating_sub(epoch_millis()));
+
+                    (deadline, timer.set_timeout(delay, deadline))
+                });
+            }
+
+            poll.poll(&mut events, None)?;
+        }
+    }
+
     pub(crate) fn worker_add(
         key: &WorkerId,
         info: &WorkerInfo,
@@ -315,80 +1353,376 @@ impl DPU {
             None => {}
         };
 
-        let WorkerInfo(capacity, queues) = info;
+        let WorkerInfo(capacity, queues) = info;
+
+        for a in multi_queue.worker_add(
+            key.clone(),
+            *capacity,
+            &queues,
+        ) {
+            assignment_queue.push_back(a)
+        };
+
+        stream.send(DaemonWorker::WorkerCreated(key.clone()));
+
+        false
+    }
+
+    pub(crate) fn interpolate(
+        state: &State,
+        cmd: &Cmd,
+        ctx: Option<&Ctx>,
+    ) -> Result<XCmd, InterpolationError> {
+        // todo: we're no longer interpolating against a single context
+        let match_arg = |x: &CmdArg| match x {
+            CmdArg::Const(v) => Ok(XCmdArg::Const(v.clone())),
+            CmdArg::Ref(CtxRef(ns, var)) =>
+                match ns {
+                    CtxNs::Curr => {
+                        let ctx = ctx.ok_or(InterpolationError::CtxNull)?;
+
+                        let v = ctx.vals.get(var);
+                        Ok(XCmdArg::Ref(XCtxRef(XCtxNs::Curr, var.clone()), v.map(|x| x.clone())))
+                    }
+                    CtxNs::Lexical => {
+                        let ctx = ctx.ok_or(InterpolationError::CtxNull)?;
+
+                        let mut frame = ctx;
+
+                        // bounded, as the chain may as well be a cycle
+                        for _ in 0..=state.contexts.len() {
+                            if let Some(v) = frame.vals.get(var) {
+                                let ns = match frame.id == ctx.id {
+                                    true => XCtxNs::Curr,
+                                    false => XCtxNs::Ref(frame.id.clone()),
+                                };
+
+                                return Ok(XCmdArg::Ref(XCtxRef(ns, var.clone()), Some(v.clone())));
+                            }
+
+                            match frame.vals.get(LOCAL_PAR_CTX).and_then(|x| x.as_str()) {
+                                Some(id) => {
+                                    frame = state.contexts.get(id).ok_or_else(
+                                        || InterpolationError::CtxMiss(id.to_string())
+                                    )?;
+                                }
+                                None => break,
+                            }
+                        }
+
+                        // not set in any of the frames, so it is set in the current one
+                        Ok(XCmdArg::Ref(XCtxRef(XCtxNs::Curr, var.clone()), None))
+                    }
+                    CtxNs::Ref(path) => {
+                        let mut ctx = ctx.ok_or(InterpolationError::CtxNull)?;
+                        let mut map: Option<&BTreeMap<String, ContextValue>> = None;
+
+                        let ref_err = |idx: usize, ident: &ContextIdent| {
+                            let ns = match idx {
+                                0 => CtxNs::Curr,
+                                _ => CtxNs::Ref(path[..idx].to_vec()),
+                            };
+
+                            InterpolationError::Ref(CtxRef(ns, ident.clone()))
+                        };
+
+                        for (idx, hop) in path.iter().enumerate() {
+                            let val = match map {
+                                Some(map) => map.get(hop),
+                                None => ctx.vals.get(hop),
+                            };
+
+                            match val {
+                                Some(ContextValue::Map(x)) => {
+                                    map = Some(x);
+                                }
+                                Some(ContextValue::Str(id)) => {
+                                    ctx = state.contexts.get(id).ok_or_else(
+                                        || InterpolationError::CtxMiss(id.to_string())
+                                    )?;
+                                    map = None;
+                                }
+                                _ => return Err(ref_err(idx, hop)),
+                            }
+                        }
+
+                        match map {
+                            // the values of a map can not be set, so the reference is not kept
+                            Some(map) => {
+                                let v = map.get(var).ok_or_else(|| ref_err(path.len(), var))?;
+                                Ok(XCmdArg::Const(v.clone()))
+                            }
+                            None => {
+                                let v = ctx.vals.get(var);
+                                Ok(XCmdArg::Ref(XCtxRef(XCtxNs::Ref(ctx.id.clone()), var.clone()), v.map(|x| x.clone())))
+                            }
+                        }
+                    }
+                }
+        };
+
+        let args: Result<Vec<XCmdArg>, InterpolationError> = cmd.args.iter().map(match_arg).collect();
+        let args = args?;
+
+        let opcode = match_arg(&cmd.opcode)?.value().and_then(|x| x.as_str().map(String::from)).ok_or(
+            InterpolationError::CmdNull
+        )?;
+
+        Ok(XCmd {
+            id: cmd.id.clone(),
+            opcode,
+            args,
+        })
+    }
+
+    /// Forget the worker, its jobs go back to their queues for the other workers to pick up.
+    pub(crate) fn worker_remove(
+        key: &WorkerId,
+        state: &mut State,
+        workers: &mut WS,
+        multi_queue: &mut MQ,
+        assignment_queue: &mut VecDeque<Ass>,
+        policies: &mut QueuePolicies,
+    ) -> bool {
+        if workers.remove(key).is_none() {
+            return false;
+        }
+
+        // the jobs that were about to be sent to it are still `Queued`
+        assignment_queue.retain(|x| &x.worker_key != key);
+
+        for ass in multi_queue.worker_remove(key) {
+            if ass.action == Action::Started {
+                assignment_queue.push_back(ass);
+                continue;
+            }
+
+            let (thread_id, step_id) = ass.job_key;
+
+            let mut thread = match state.threads.get(&thread_id) {
+                Some(x) if x.step == step_id => x.clone(),
+                _ => continue,
+            };
+
+            thread.state = match &thread.state {
+                ThreadState::Assigned(command, _) => ThreadState::Queued(command.clone()),
+                _ => continue,
+            };
+
+            state.insert_thread(thread);
+
+            // the timeout starts over once the job is assigned again
+            policies.assigned.retain(|(_, x, y)| !(x == &thread_id && *y == step_id));
+        }
+
+        true
+    }
+
+    /// Take the job off its queue or its worker, handing the capacity it frees out.
+    fn job_finish(
+        queue_id: &CommandId,
+        job_key: &(ThreadId, StepId),
+        assignment_queue: &mut VecDeque<Ass>,
+        multi_queue: &mut MQ,
+    ) {
+        for ass in multi_queue.job_finish(queue_id, job_key) {
+            if ass.action == Action::Started {
+                assignment_queue.push_back(ass);
+            }
+        }
+    }
+
+    pub(crate) fn raise(
+        thread_id: &ThreadId,
+        payload: ContextValue,
+        state: &mut State,
+        assignment_queue: &mut VecDeque<Ass>,
+        multi_queue: &mut MQ,
+    ) -> bool {
+        let mut thread = match state.threads.get(thread_id) {
+            Some(x) => x.clone(),
+            None => return false,
+        };
+
+        let job_key = (thread.id.clone(), thread.step);
+
+        match &thread.state {
+            ThreadState::Exited(_) => return false,
+            ThreadState::Paused(pause_id) => {
+                state.remove_pause(pause_id);
+            }
+            // the result the worker might still send is dropped, as the handler is a step further
+            ThreadState::Queued(command) | ThreadState::Assigned(command, _) => {
+                assignment_queue.retain(|x| x.job_key != job_key);
+
+                DPU::job_finish(&command.opcode, &job_key, assignment_queue, multi_queue);
+            }
+            _ => {}
+        }
+
+        thread.state = ThreadState::Err(ThreadError::Raised(payload));
+        state.insert_thread(thread);
+
+        DPU::proceed(
+            thread_id,
+            state,
+            assignment_queue,
+            multi_queue,
+        );
+
+        true
+    }
+
+    pub(crate) fn unpause(
+        pause_id: &PauseId,
+        vals: HashMap<ContextIdent, ContextValue>,
+        state: &mut State,
+        assignment_queue: &mut VecDeque<Ass>,
+        multi_queue: &mut MQ,
+    ) -> bool {
+        let pause = match state.pauses.get(pause_id) {
+            Some(x) => x.clone(),
+            None => return false,
+        };
+
+        let mut thread = match state.threads.get(&pause.thread) {
+            Some(x) => x.clone(),
+            None => {
+                state.remove_pause(pause_id);
+                return false;
+            }
+        };
+
+        match &thread.state {
+            ThreadState::Paused(x) if x == pause_id => {}
+            ThreadState::Exited(_) => {
+                state.remove_pause(pause_id);
+                return false;
+            }
+            _ => {
+                // only the first unpause is kept until the thread pauses, it carries on right away then
+                if pause.unpaused.is_some() {
+                    return false;
+                }
+
+                state.insert_pause(pause_id, Pause { thread: pause.thread, unpaused: Some(vals) });
+
+                return true;
+            }
+        }
+
+        let mut tx = Transaction::new(state);
+        tx.pause_remove(pause_id);
+        tx.thread_context_extend(&mut thread, vals);
+        tx.commit();
+
+        thread.state = ThreadState::Fetching(thread.ip.clone());
+        state.insert_thread(thread);
+
+        DPU::proceed(
+            &pause.thread,
+            state,
+            assignment_queue,
+            multi_queue,
+        );
+
+        true
+    }
+
+    pub(crate) fn wake_until(
+        now: u64,
+        state: &mut State,
+        assignment_queue: &mut VecDeque<Ass>,
+        multi_queue: &mut MQ,
+    ) -> usize {
+        let mut ret = 0;
+
+        while let Some((deadline, thread_id)) = state.sleeping.iter().next().cloned() {
+            if deadline > now {
+                break;
+            }
+
+            state.sleeping.remove(&(deadline, thread_id.clone()));
+
+            let mut thread = match state.threads.get(&thread_id) {
+                Some(x) => x.clone(),
+                None => continue,
+            };
 
-        for a in multi_queue.worker_add(
-            key.clone(),
-            *capacity,
-            &queues,
-        ) {
-            assignment_queue.push_back(a)
-        };
+            thread.state = match &thread.state {
+                ThreadState::Sleeping(x) if *x == deadline => ThreadState::Fetching(thread.ip.clone()),
+                // the step goes up once it is queued again
+                ThreadState::Delayed(command, x) if *x == deadline => ThreadState::Interpolated(command.clone()),
+                _ => continue,
+            };
 
-        stream.send(DaemonWorker::WorkerCreated(key.clone()));
+            state.insert_thread(thread);
 
-        false
+            DPU::proceed(
+                &thread_id,
+                state,
+                assignment_queue,
+                multi_queue,
+            );
+
+            ret += 1;
+        }
+
+        ret
     }
 
-    pub(crate) fn interpolate(
-        state: &State,
-        cmd: &Cmd,
-        ctx: Option<&Ctx>,
-    ) -> Result<XCmd, InterpolationError> {
-        // todo: we're no longer interpolating against a single context
-        let match_arg = |x: &CmdArg| match x {
-            CmdArg::Const(v) => Ok(XCmdArg::Const(v.clone())),
-            CmdArg::Ref(CtxRef(ns, var)) =>
-                match ns {
-                    CtxNs::Curr => {
-                        let ctx = ctx.ok_or(InterpolationError::CtxNull)?;
+    pub(crate) fn expire_until(
+        now: u64,
+        state: &mut State,
+        assignment_queue: &mut VecDeque<Ass>,
+        multi_queue: &mut MQ,
+        policies: &mut QueuePolicies,
+    ) -> usize {
+        let mut ret = 0;
 
-                        let v = ctx.vals.get(var);
-                        Ok(XCmdArg::Ref(XCtxRef(XCtxNs::Curr, var.clone()), v.map(|x| x.clone())))
-                    }
-                    CtxNs::Ref(ns_k) => {
-                        let ctx = state.contexts.get(ns_k).ok_or(
-                            InterpolationError::CtxMiss(ns_k.clone())
-                        )?;
+        while let Some((deadline, thread_id, step_id)) = policies.assigned.iter().next().cloned() {
+            if deadline > now {
+                break;
+            }
 
-                        let v = ctx.vals.get(var);
-                        Ok(XCmdArg::Ref(XCtxRef(XCtxNs::Ref(ns_k.clone()), var.clone()), v.map(|x| x.clone())))
-                    }
-                }
-        };
+            policies.assigned.remove(&(deadline, thread_id.clone(), step_id));
 
-        let args: Result<Vec<XCmdArg>, InterpolationError> = cmd.args.iter().map(match_arg).collect();
-        let args = args?;
+            let mut thread = match state.threads.get(&thread_id) {
+                Some(x) if x.step == step_id => x.clone(),
+                _ => continue,
+            };
 
-        let opcode = match_arg(&cmd.opcode)?.value().ok_or(
-            InterpolationError::CmdNull
-        )?;
+            let (command, worker_id) = match &thread.state {
+                ThreadState::Assigned(command, worker_id) => (command.clone(), worker_id.clone()),
+                _ => continue,
+            };
 
-        Ok(XCmd {
-            id: cmd.id.clone(),
-            opcode,
-            args,
-        })
-    }
+            // the worker is free to take other jobs, whatever it returns for this one is dropped
+            DPU::job_finish(&command.opcode, &(thread_id.clone(), step_id), assignment_queue, multi_queue);
 
-    pub(crate) fn worker_remove(
-        key: &WorkerId,
-        workers: &mut WS,
-        multi_queue: &mut MQ,
-        assignment_queue: &mut VecDeque<Ass>,
-    ) -> bool {
-        match workers.remove(key) {
-            Some(_) => return true,
-            None => {
-                return false;
+            let requeue = policies.timeouts.get(&command.opcode).map(|x| x.requeue).unwrap_or(0);
+
+            if thread.timeouts < requeue {
+                // the step goes up once it is queued again
+                thread.timeouts += 1;
+                thread.state = ThreadState::Interpolated(command);
+            } else {
+                thread.state = ThreadState::Err(ThreadError::Timeout { queue: command.opcode, worker: worker_id });
             }
-        }
 
-        for a in multi_queue.worker_remove(key) {
-            assignment_queue.push_back(a);
-        };
+            state.insert_thread(thread);
 
-        true
+            DPU::proceed(
+                &thread_id,
+                state,
+                assignment_queue,
+                multi_queue,
+            );
+
+            ret += 1;
+        }
+
+        ret
     }
 
     pub(crate) fn job_add(
@@ -397,7 +1731,7 @@ impl DPU {
         state: &mut State,
         assignment_queue: &mut VecDeque<Ass>,
         multi_queue: &mut MQ,
-    ) -> ThreadId {
+    ) -> (ThreadId, Receiver<ThreadExit>) {
         let id = state.create_id();
 
         let thread = Thread::create(
@@ -410,6 +1744,10 @@ impl DPU {
             thread
         );
 
+        // subscribe before proceeding, since the thread may exit right away
+        let (tx, rx) = channel::<ThreadExit>();
+        state.thread_subscribe(&id, ThreadSubscriber::Local(tx));
+
         DPU::proceed(
             &id,
             state,
@@ -417,37 +1755,115 @@ impl DPU {
             multi_queue,
         );
 
-        id.clone()
+        (id, rx)
+    }
+
+    /// Write the changes made so far into the log.
+    ///
+    /// Going on without the changes being durable would lose them in a crash, so a failed write
+    /// is returned by the next `DPU::process`, see `Wal::failure`.
+    pub(crate) fn commit(
+        wal: &mut Option<Wal>,
+        state: &mut State,
+    ) {
+        let entry = match state.take_changes() {
+            Some(x) => x,
+            None => return,
+        };
+
+        if let Some(wal) = wal {
+            let _ = wal.append(&entry, state);
+        }
+    }
+
+    /// The error the log had failed with, if any.
+    fn failure(&self) -> io::Result<()> {
+        match self.wal.as_ref().and_then(Wal::failure) {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
     }
 
     pub(crate) fn process_channel(
         receiver: &Receiver<DaemonRequest>,
+        wal: &mut Option<Wal>,
         state: &mut State,
         assignment_queue: &mut VecDeque<Ass>,
         workers: &mut WS,
         multi_queue: &mut MQ,
-    ) {
-        while let Ok(pkt) = receiver.try_recv() {
+        policies: &mut QueuePolicies,
+    ) -> Processed {
+        let mut processed = 0;
+
+        loop {
+            // the rest is left in the channel, the daemon stops on the failure
+            if wal.as_ref().and_then(Wal::failure).is_some() {
+                break;
+            }
+
+            let pkt = match receiver.try_recv() {
+                Ok(x) => x,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Processed::Shutdown,
+            };
+
+            processed += 1;
 
             match pkt {
-                DaemonRequest::Finished(wid, thread_id, step_id, queue_id, res) => {
-                    let thread = state.threads.get_mut(&thread_id).unwrap();
+                DaemonRequest::Finished(wid, thread_id, step_id, _, res) => {
+                    // the step might have been requeued since, e.g. after a restart, in which case
+                    // the result is either applied already or will be produced again
+                    let thread = match state.threads.get_mut(&thread_id) {
+                        Some(x) if x.step == step_id && x.is_waiting() => x,
+                        _ => continue,
+                    };
 
-                    assert_eq!(step_id, thread.step);
+                    // only the worker the job is assigned to has a result for it: the job might have
+                    // been taken from a removed worker, and be queued or handed to another one since
+                    let command = match &thread.state {
+                        ThreadState::Assigned(cmd, x) if x == &wid => cmd.clone(),
+                        _ => continue,
+                    };
+
+                    // the job is taken off the queue it was created on, whatever the worker sends back
+                    DPU::job_finish(&command.opcode, &(thread_id.clone(), step_id), assignment_queue, multi_queue);
+
+                    let delay = match (&res, policies.retries.get(&command.opcode)) {
+                        (Err(err), Some(policy)) => policy.delay(err, thread.retries),
+                        _ => None,
+                    };
 
-                    thread.state = ThreadState::Done(res);
+                    thread.state = match delay {
+                        Some(delay) => {
+                            thread.retries += 1;
+                            thread.timeouts = 0;
+
+                            // the same command is queued again, the step goes up with it
+                            match delay {
+                                0 => ThreadState::Interpolated(command),
+                                _ => ThreadState::Delayed(command, epoch_millis().saturating_add(delay)),
+                            }
+                        }
+                        None => {
+                            thread.retries = 0;
+                            ThreadState::Done(res)
+                        }
+                    };
 
-                    multi_queue.job_finish(&queue_id, &(thread_id.clone(), step_id));
+                    // the index of the delayed threads is kept up to date
+                    let thread = thread.clone();
+                    state.insert_thread(thread);
 
                     DPU::proceed(
                         &thread_id,
                         state,
                         assignment_queue,
                         multi_queue,
-                    )
+                    );
+
+                    DPU::commit(wal, state);
                 }
                 DaemonRequest::WorkerAdd(info, chan_rep) => {
-                    eprintln!("wadd");
                     let id = state.create_id();
 
                     DPU::worker_add(
@@ -462,23 +1878,141 @@ impl DPU {
                 DaemonRequest::WorkerRemove(wrkr) => {
                     DPU::worker_remove(
                         &wrkr,
+                        state,
                         workers,
                         multi_queue,
                         assignment_queue,
+                        policies,
+                    );
+
+                    DPU::commit(wal, state);
+                }
+                DaemonRequest::Client(idx, rq, chan_rep) => {
+                    let rp = DPU::client_request(
+                        idx,
+                        rq,
+                        &chan_rep,
+                        state,
+                        assignment_queue,
+                        multi_queue,
+                    );
+
+                    DPU::commit(wal, state);
+
+                    // the client might have disconnected in the meantime
+                    if let Some(rp) = rp {
+                        let _ = chan_rep.send(DaemonClient::Response(idx, rp));
+                    }
+                }
+                DaemonRequest::Raise(thread_id, payload) => {
+                    DPU::raise(
+                        &thread_id,
+                        payload,
+                        state,
+                        assignment_queue,
+                        multi_queue,
+                    );
+
+                    DPU::commit(wal, state);
+                }
+                DaemonRequest::Unpause(pause_id, vals) => {
+                    DPU::unpause(
+                        &pause_id,
+                        vals,
+                        state,
+                        assignment_queue,
+                        multi_queue,
                     );
+
+                    DPU::commit(wal, state);
+                }
+                DaemonRequest::Shutdown => {
+                    return Processed::Shutdown;
                 }
-                // todo enable exceptional condition handling from external (e.g. enable an exception to be raised in a running task)
-                // todo enable unpausing threads
             }
         }
 
-        // todo we need to make sure that we process the assignment queue every time we exit
+        Processed::Requests(processed)
+    }
+
+    pub(crate) fn client_request(
+        idx: usize,
+        rq: ClientRequest,
+        chan_rep: &Sender<DaemonClient>,
+        state: &mut State,
+        assignment_queue: &mut VecDeque<Ass>,
+        multi_queue: &mut MQ,
+    ) -> Option<ClientResponse> {
+        match rq {
+            ClientRequest::CommandsAdd(commands) => {
+                state.insert_commands(commands.iter());
+
+                Some(ClientResponse::CommandsAdded(commands.len()))
+            }
+            ClientRequest::ContextCreate(vals) => {
+                let id = state.create_id();
+
+                state.insert_context(&Ctx::create(id.clone(), vals));
+
+                Some(ClientResponse::ContextCreated(id))
+            }
+            ClientRequest::ThreadCreate(ep, ctx) => {
+                let (id, _) = DPU::job_add(
+                    ep,
+                    ctx,
+                    state,
+                    assignment_queue,
+                    multi_queue,
+                );
+
+                Some(ClientResponse::ThreadCreated(id))
+            }
+            ClientRequest::ThreadStatus(id) => {
+                let status = state.threads.get(&id).map(|x| x.status());
+
+                Some(ClientResponse::ThreadStatus(id, status))
+            }
+            ClientRequest::ThreadRaise(id, payload) => {
+                DPU::raise(
+                    &id,
+                    payload,
+                    state,
+                    assignment_queue,
+                    multi_queue,
+                );
+
+                let status = state.threads.get(&id).map(|x| x.status());
+
+                Some(ClientResponse::ThreadStatus(id, status))
+            }
+            ClientRequest::ThreadUnpause(id, vals) => {
+                let unpaused = DPU::unpause(
+                    &id,
+                    vals,
+                    state,
+                    assignment_queue,
+                    multi_queue,
+                );
+
+                Some(ClientResponse::ThreadUnpaused(id, unpaused))
+            }
+            ClientRequest::ThreadWait(id) => {
+                let subscriber = ThreadSubscriber::Client(idx, chan_rep.clone());
+
+                if state.thread_subscribe(&id, subscriber) {
+                    None
+                } else {
+                    Some(ClientResponse::ThreadStatus(id, None))
+                }
+            }
+        }
     }
 
     pub(crate) fn process_assignments(
         state: &mut State,
         assignment_queue: &mut VecDeque<Ass>,
         workers: &mut WS,
+        policies: &mut QueuePolicies,
     ) {
         let drained = assignment_queue.drain(..);
 
@@ -491,7 +2025,7 @@ impl DPU {
             let thread = state.threads.get_mut(&thread_id).unwrap();
 
             let command = match &thread.state {
-                ThreadState::Queued(cmd) => cmd,
+                ThreadState::Queued(cmd) => cmd.clone(),
                 _ => panic!("{:?}", thread.state)
             };
 
@@ -499,7 +2033,16 @@ impl DPU {
 
             assert_eq!(step_id, thread.step);
 
-            worker.stream.send(DaemonWorker::JobAssigned(thread_id, step_id, command.id.clone(), command.clone()));
+            if let Some(timeout) = policies.timeouts.get(&ass.queue_key) {
+                let deadline = epoch_millis().saturating_add(timeout.after);
+
+                policies.assigned.insert((deadline, thread_id.clone(), step_id));
+            }
+
+            // the queue is sent back with the result, so that the job is taken off the right one
+            worker.stream.send(DaemonWorker::JobAssigned(thread_id, step_id, ass.queue_key.clone(), command.clone()));
+
+            thread.state = ThreadState::Assigned(command, ass.worker_key);
         }
     }
 
@@ -517,20 +2060,22 @@ impl DPU {
                     Some(ThreadState::Fetching(thread.ip.clone()))
                 }
                 ThreadState::Done(res) => {
+                    thread.timeouts = 0;
+
                     let res = res.clone();
                     let res =
                         res.map_err(|res| ThreadError::WorkerDuring(res.clone()));
                     let res =
                         res.and_then(
                             |res|
-                                DPU::exec(&mut thread, state, &res).map_err(
+                                DPU::exec(&mut thread, state, assignment_queue, multi_queue, &res).map_err(
                                     |err| ThreadError::WorkerPost(err)
                                 )
                         );
 
                     match res {
-                        Ok(_) => {
-                            Some(ThreadState::Fetching(thread.ip.clone()))
+                        Ok(next) => {
+                            Some(next)
                         }
                         Err(err) => {
                             Some(ThreadState::Err(err))
@@ -568,8 +2113,11 @@ impl DPU {
                 ThreadState::Interpolated(command) => {
                     thread.step = thread.step.wrapping_add(1);
 
-                    let assignment =
-                        multi_queue.job_create(&command.opcode, &(thread.id.clone(), thread.step));
+                    let assignment = multi_queue.job_create(
+                        &command.opcode,
+                        &(thread.id.clone(), thread.step),
+                        thread.priority,
+                    );
 
                     for val in assignment {
                         assignment_queue.push_back(val);
@@ -587,42 +2135,26 @@ impl DPU {
                 ThreadState::Paused(_) => {
                     None
                 }
+                ThreadState::Sleeping(_) | ThreadState::Delayed(_, _) => {
+                    None
+                }
                 ThreadState::Err(error) => {
                     match &thread.eip {
                         Some(eip) => {
-                            let err_str = format!("{:?}", error);
-
+                            // returning from the handler retries the command that failed
                             Some(ThreadState::Done(Ok(vec![
-                                Op::LocalSet(
-                                    "exc".into(),
-                                    // todo serialize exception value into json string
-                                    RValue::Local(RValueLocal::Const(err_str)),
-                                ),
-                                Op::LocalSet(
-                                    "new_ctx".into(),
-                                    RValue::Extern(RValueExtern::ContextCreate),
+                                Op::Call(
+                                    RValueLocal::Const(eip.clone().into()),
+                                    RValueLocal::Ref(LOCAL_NIP.into()),
                                 ),
                                 Op::ContextSet(
-                                    RValueLocal::Ref("new_ctx".into()),
-                                    RValueLocal::Const(LOCAL_PAR_CTX.into()),
                                     RValueLocal::Ref(LOCAL_CTX.into()),
-                                ),
-                                Op::ContextSet(
-                                    RValueLocal::Ref("new_ctx".into()),
-                                    RValueLocal::Const(LOCAL_PAR_IP.into()),
-                                    RValueLocal::Ref(LOCAL_NIP.into()),
-                                ),
-                                Op::LocalSet(
-                                    LOCAL_CTX.into(),
-                                    RValue::Local(RValueLocal::Ref("new_ctx".into())),
-                                ),
-                                Op::LocalSet(
-                                    LOCAL_NIP.into(),
-                                    RValue::Local(RValueLocal::Const(eip.clone())),
+                                    RValueLocal::Const(LOCAL_EXC.into()),
+                                    RValueLocal::Const(error.to_value()),
                                 ),
                                 Op::LocalSet(
                                     LOCAL_EIP.into(),
-                                    RValue::Local(RValueLocal::Const("".into())),
+                                    RValue::Local(RValueLocal::Const(ContextValue::Null)),
                                 )
                             ])))
                         }
@@ -657,19 +2189,32 @@ impl DPU {
             }
         }
 
-        state.threads.insert(thread_id.clone(), thread);
+        state.thread_exited(&thread);
+        state.insert_thread(thread);
     }
 
+    /// Apply the ops returned by a worker, returning the state the thread moves into.
+    ///
+    /// Either every op is applied, or none of them is.
     fn exec(
         thread: &mut Thread,
         state: &mut State,
+        assignment_queue: &mut VecDeque<Ass>,
+        multi_queue: &mut MQ,
         ops: &Vec<Op>,
-    ) -> Result<(), OpErr> {
+    ) -> Result<ThreadState, OpErr> {
+        let mut exited = false;
+        let mut paused = None;
+        let mut deadline = None;
+
         let mut locals = HashMap::<ContextIdent, ContextValue>::default();
 
-        locals.insert(LOCAL_NIP.to_string(), thread.ip.clone());
-        locals.insert(LOCAL_EIP.to_string(), thread.eip.clone().unwrap_or("".to_string()));
-        locals.insert(LOCAL_CTX.to_string(), thread.ctx.clone().unwrap_or("".to_string()));
+        locals.insert(LOCAL_TID.to_string(), thread.id.clone().into());
+        locals.insert(LOCAL_NIP.to_string(), thread.ip.clone().into());
+        locals.insert(LOCAL_EIP.to_string(), thread.eip.clone().into());
+        locals.insert(LOCAL_CTX.to_string(), thread.ctx.clone().into());
+
+        let mut tx = Transaction::new(state);
 
         for (op_index, op) in ops.iter().enumerate() {
             let map_err_fn = |op_reason| OpErr { op_index: Some(op_index), op_reason };
@@ -678,7 +2223,7 @@ impl DPU {
                 Op::LocalSet(loc_ident, rval) => {
                     locals.insert(
                         loc_ident.clone(),
-                        rval.resolve(&locals, state).map_err(map_err_fn)?,
+                        rval.resolve(&locals, &mut tx).map_err(map_err_fn)?,
                     );
                 }
 //                Op::ContextSet(loc_ident, rval) => {
@@ -694,51 +2239,236 @@ impl DPU {
 //                    );
 //                }
                 Op::ContextSet(ctx_ident, ctx_val_ident, rval) => {
-                    let ctx_ident = ctx_ident.resolve(&locals).map_err(map_err_fn)?;
-                    let ctx_val_ident = ctx_val_ident.resolve(&locals).map_err(map_err_fn)?;
+                    let ctx_ident = ctx_ident.resolve_id(&locals).map_err(map_err_fn)?;
+                    let ctx_val_ident = ctx_val_ident.resolve_id(&locals).map_err(map_err_fn)?;
                     let rval = rval.resolve(&locals).map_err(map_err_fn)?;
 
-                    match state.contexts.get_mut(&ctx_ident) {
+                    match tx.context_mut(&ctx_ident) {
                         Some(context) => context.vals.insert(ctx_val_ident, rval),
                         None => {
                             return Err(map_err_fn(OpErrReason::ContextRefInvalid { ident: ctx_ident }));
                         }
                     };
                 }
-                Op::ContextRemove(rval) => {
+                Op::ContextCompareAndSet(ctx_ident, ctx_val_ident, expected, rval) => {
+                    let ctx_ident = ctx_ident.resolve_id(&locals).map_err(map_err_fn)?;
+                    let ctx_val_ident = ctx_val_ident.resolve_id(&locals).map_err(map_err_fn)?;
+                    let expected = match expected {
+                        Some(x) => Some(x.resolve(&locals).map_err(map_err_fn)?),
+                        None => None,
+                    };
                     let rval = rval.resolve(&locals).map_err(map_err_fn)?;
 
-                    match state.contexts.remove(&rval) {
-                        Some(_) => {}
+                    let context = match tx.context_mut(&ctx_ident) {
+                        Some(x) => x,
+                        None => {
+                            return Err(map_err_fn(OpErrReason::ContextRefInvalid { ident: ctx_ident }));
+                        }
+                    };
+
+                    let found = context.vals.get(&ctx_val_ident).cloned();
+
+                    if found != expected {
+                        return Err(map_err_fn(OpErrReason::CompareFailed { ident: ctx_val_ident, expected, found }));
+                    }
+
+                    context.vals.insert(ctx_val_ident, rval);
+                }
+                Op::ContextSetMany(ctx_ident, pairs) => {
+                    let ctx_ident = ctx_ident.resolve_id(&locals).map_err(map_err_fn)?;
+
+                    let mut vals = Vec::<(ContextIdent, ContextValue)>::with_capacity(pairs.len());
+
+                    for (ctx_val_ident, rval) in pairs {
+                        vals.push((
+                            ctx_val_ident.resolve_id(&locals).map_err(map_err_fn)?,
+                            rval.resolve(&locals).map_err(map_err_fn)?,
+                        ));
+                    }
+
+                    match tx.context_mut(&ctx_ident) {
+                        Some(context) => context.vals.extend(vals),
                         None => {
-                            return Err(map_err_fn(OpErrReason::ContextDoesNotExist { id: rval }));
+                            return Err(map_err_fn(OpErrReason::ContextSetManyInvalid { id: ctx_ident }));
                         }
                     };
                 }
+                Op::ContextUnset(ctx_ident, ctx_val_ident) => {
+                    let ctx_ident = ctx_ident.resolve_id(&locals).map_err(map_err_fn)?;
+                    let ctx_val_ident = ctx_val_ident.resolve_id(&locals).map_err(map_err_fn)?;
 
-                Op::ThreadRemove(rval) => {
+                    let context = match tx.context_mut(&ctx_ident) {
+                        Some(x) => x,
+                        None => {
+                            return Err(map_err_fn(OpErrReason::ContextDoesNotExist { id: ctx_ident }));
+                        }
+                    };
+
+                    if context.vals.remove(&ctx_val_ident).is_none() {
+                        return Err(map_err_fn(OpErrReason::ContextKeyDoesNotExist { id: ctx_ident, ident: ctx_val_ident }));
+                    }
+                }
+                Op::ContextMerge(src, dst) => {
+                    let src = src.resolve_id(&locals).map_err(map_err_fn)?;
+                    let dst = dst.resolve_id(&locals).map_err(map_err_fn)?;
+
+                    let vals = match tx.context(&src) {
+                        Some(x) => x.vals.clone(),
+                        None => {
+                            return Err(map_err_fn(OpErrReason::ContextMergeInvalid { id: src }));
+                        }
+                    };
+
+                    match tx.context_mut(&dst) {
+                        Some(context) => context.vals.extend(vals),
+                        None => {
+                            return Err(map_err_fn(OpErrReason::ContextMergeInvalid { id: dst }));
+                        }
+                    };
+                }
+                Op::ListAppend(ctx_ident, ctx_val_ident, rval) => {
+                    let ctx_ident = ctx_ident.resolve_id(&locals).map_err(map_err_fn)?;
+                    let ctx_val_ident = ctx_val_ident.resolve_id(&locals).map_err(map_err_fn)?;
                     let rval = rval.resolve(&locals).map_err(map_err_fn)?;
 
-                    match state.threads.remove(&rval) {
-                        Some(_) => {}
+                    let context = match tx.context_mut(&ctx_ident) {
+                        Some(x) => x,
+                        None => {
+                            return Err(map_err_fn(OpErrReason::ContextRefInvalid { ident: ctx_ident }));
+                        }
+                    };
+
+                    match context.vals.get_mut(&ctx_val_ident) {
+                        Some(ContextValue::List(list)) => list.push(rval),
+                        _ => {
+                            return Err(map_err_fn(OpErrReason::ListInvalid { id: ctx_ident, ident: ctx_val_ident }));
+                        }
+                    };
+                }
+                Op::Call(ip, ret) => {
+                    let ip = ip.resolve_id(&locals).map_err(map_err_fn)?;
+                    let ret = ret.resolve_id(&locals).map_err(map_err_fn)?;
+
+                    let mut vals = HashMap::<ContextIdent, ContextValue>::default();
+                    vals.insert(LOCAL_PAR_CTX.into(), locals.get(LOCAL_CTX).unwrap().clone());
+                    vals.insert(LOCAL_PAR_IP.into(), ret.into());
+
+                    let id: ContextId = tx.create_id().to_string();
+                    tx.context_insert(Ctx::create(id.clone(), vals));
+
+                    locals.insert(LOCAL_CTX.into(), id.into());
+                    locals.insert(LOCAL_NIP.into(), ip.into());
+                }
+                Op::Return => {
+                    let ctx_ident = RValueLocal::Ref(LOCAL_CTX.into()).resolve_id(&locals).map_err(map_err_fn)?;
+
+                    let frame = match tx.context(&ctx_ident) {
+                        Some(x) => x,
                         None => {
-                            return Err(map_err_fn(OpErrReason::ThreadDoesNotExist { id: rval }));
+                            return Err(map_err_fn(OpErrReason::ContextRefInvalid { ident: ctx_ident }));
+                        }
+                    };
+
+                    // the thread may have been started without a context, then the parent is null
+                    let (ctx, ip) = match (frame.vals.get(LOCAL_PAR_CTX), frame.vals.get(LOCAL_PAR_IP)) {
+                        (Some(ctx), Some(ContextValue::Str(ip))) if ctx.is_null() || ctx.as_str().is_some() => {
+                            (ctx.clone(), ip.clone())
+                        }
+                        _ => {
+                            return Err(map_err_fn(OpErrReason::FrameInvalid { id: ctx_ident }));
+                        }
+                    };
+
+                    tx.context_remove(&ctx_ident);
+
+                    locals.insert(LOCAL_CTX.into(), ctx);
+                    locals.insert(LOCAL_NIP.into(), ip.into());
+                }
+                Op::ContextRemove(rval) => {
+                    let rval = rval.resolve_id(&locals).map_err(map_err_fn)?;
+
+                    if !tx.context_remove(&rval) {
+                        return Err(map_err_fn(OpErrReason::ContextDoesNotExist { id: rval }));
+                    }
+                }
+
+                Op::ThreadRemove(rval) => {
+                    let rval = rval.resolve_id(&locals).map_err(map_err_fn)?;
+
+                    if !tx.thread_remove(&rval) {
+                        return Err(map_err_fn(OpErrReason::ThreadDoesNotExist { id: rval }));
+                    }
+                }
+                Op::ThreadPause(rval) => {
+                    let rval = rval.resolve_id(&locals).map_err(map_err_fn)?;
+
+                    match tx.pause(&rval) {
+                        Some(pause) if pause.thread == thread.id => {}
+                        _ => {
+                            return Err(map_err_fn(OpErrReason::PauseInvalid { id: rval }));
                         }
+                    }
+
+                    paused = Some(rval);
+                }
+                Op::Sleep(rval) => {
+                    let rval = rval.resolve(&locals).map_err(map_err_fn)?;
+
+                    let millis = match rval.as_int() {
+                        Some(x) if x >= 0 => x as u64,
+                        _ => return Err(map_err_fn(OpErrReason::DeadlineInvalid(rval))),
                     };
+
+                    deadline = Some(epoch_millis().saturating_add(millis));
+                }
+                Op::ResumeAt(rval) => {
+                    let rval = rval.resolve(&locals).map_err(map_err_fn)?;
+
+                    match rval.as_int() {
+                        Some(x) if x >= 0 => deadline = Some(x as u64),
+                        _ => return Err(map_err_fn(OpErrReason::DeadlineInvalid(rval))),
+                    }
+                }
+                Op::ThreadExit => {
+                    exited = true;
                 }
             }
         }
 
-        thread.ip = locals.get(&LOCAL_NIP.to_string()).unwrap().clone();
-        thread.eip = match locals.get(&LOCAL_EIP.to_string()).unwrap().as_ref() {
-            "" => None,
-            x => Some(x.to_string())
-        };
-        thread.ctx = match locals.get(&LOCAL_CTX.to_string()).unwrap().as_ref() {
-            "" => None,
-            x => Some(x.to_string())
-        };
+        let local_id = |ident: &str| locals.get(ident).unwrap().as_str().map(String::from);
+
+        thread.ip = local_id(LOCAL_NIP).ok_or(OpErr { op_index: None, op_reason: OpErrReason::IdNull })?;
+        thread.eip = local_id(LOCAL_EIP);
+        thread.ctx = local_id(LOCAL_CTX);
+
+        // the unpause might have arrived already
+        let unpaused = paused.as_ref().and_then(|x| tx.pause(x)).and_then(|x| x.unpaused.clone());
 
-        Ok(())
+        if let Some(vals) = unpaused {
+            tx.pause_remove(paused.as_ref().unwrap());
+            tx.thread_context_extend(thread, vals);
+            paused = None;
+        }
+
+        // the jobs of the waiting threads that were removed are taken off the queues, as on a raise
+        for removed in tx.commit() {
+            if let ThreadState::Queued(command) | ThreadState::Assigned(command, _) = &removed.state {
+                let job_key = (removed.id.clone(), removed.step);
+
+                assignment_queue.retain(|x| x.job_key != job_key);
+
+                DPU::job_finish(&command.opcode, &job_key, assignment_queue, multi_queue);
+            }
+        }
+
+        if exited {
+            Ok(ThreadState::Exited(Ok(())))
+        } else if let Some(pause_id) = paused {
+            Ok(ThreadState::Paused(pause_id))
+        } else if let Some(deadline) = deadline {
+            Ok(ThreadState::Sleeping(deadline))
+        } else {
+            Ok(ThreadState::Fetching(thread.ip.clone()))
+        }
     }
 }
@@ -13,6 +13,8 @@ pub mod prog;
 pub mod daemon;
 pub mod pubsub;
 pub mod worker;
+pub mod builtin;
+pub mod wal;
 pub mod net;
 
 //pub use obj;
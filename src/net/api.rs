@@ -0,0 +1,244 @@
+use std::thread::spawn;
+use std::collections::HashMap;
+use std::io::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::mpsc::TryRecvError;
+use std::time::Duration;
+
+use mio::{Events, Poll, PollOpt, Ready, Token};
+use mio::net::TcpListener;
+use mio::tcp::TcpStream;
+use mio_extras::channel::{Sender, Receiver, channel};
+use nom::Err as NomErr;
+use serde_derive::{Serialize, Deserialize};
+use serde_json::Error as SerdeError;
+
+use crate::daemon::*;
+use crate::net::parser::*;
+use crate::net::tcp::*;
+use crate::net::util::*;
+
+/// Requests sent by the clients of the daemon (as opposed to the workers).
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum ClientApiRq {
+    Request(usize, ClientRequest),
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum ClientApiRp {
+    Response(usize, ClientResponse),
+}
+
+impl StreamReadable for ClientApiRq {
+    fn read(buffer: &[u8]) -> Result<(&[u8], Self), NomErr<&[u8], u32>> {
+        read_json_packet(buffer)
+    }
+}
+
+impl StreamWritable<SerdeError> for ClientApiRq {
+    fn write(&self) -> Result<Vec<u8>, SerdeError> {
+        write_json_packet(self)
+    }
+}
+
+impl StreamReadable for ClientApiRp {
+    fn read(buffer: &[u8]) -> Result<(&[u8], Self), NomErr<&[u8], u32>> {
+        read_json_packet(buffer)
+    }
+}
+
+impl StreamWritable<SerdeError> for ClientApiRp {
+    fn write(&self) -> Result<Vec<u8>, SerdeError> {
+        write_json_packet(self)
+    }
+}
+
+struct ApiClient {
+    rx: Receiver<ClientApiRq>,
+    tx: Sender<ClientApiRp>,
+    rrx: Receiver<DaemonClient>,
+    rtx: Sender<DaemonClient>,
+    chan: StreamForwarder<TcpStream, ClientApiRq, ClientApiRp, SerdeError>,
+}
+
+struct ApiListener {
+    listener: TcpListener,
+    master_tx: Sender<DaemonRequest>,
+    poll: Poll,
+    l_rcvr: Receiver<ListenerRq>,
+    tok_ctr: usize,
+    clients: HashMap<usize, ApiClient>,
+}
+
+const TOK_PER_BLOCK: usize = 5;
+
+impl ApiListener {
+    pub fn new(
+        addr: &SocketAddr,
+        master_tx: Sender<DaemonRequest>,
+        l_rcvr: Receiver<ListenerRq>,
+    ) -> Result<ApiListener, Error> {
+        let listener = TcpListener::bind(addr)?;
+
+        let poll = Poll::new()?;
+
+        poll.register(&listener, Token(0), Ready::readable(), PollOpt::edge())?;
+        poll.register(&l_rcvr, Token(1), Ready::readable(), PollOpt::edge())?;
+
+        Ok(
+            ApiListener {
+                listener,
+                master_tx,
+                poll,
+                l_rcvr,
+                tok_ctr: 1,
+                clients: HashMap::<usize, ApiClient>::new(),
+            }
+        )
+    }
+
+    fn register(&mut self, client: ApiClient) -> Result<(), io::Error> {
+        let idx = self.tok_ctr + 1;
+        self.tok_ctr = self.tok_ctr.wrapping_add(1);
+
+        let tok_begin = idx * TOK_PER_BLOCK;
+
+        self.poll.register(&client.chan.bk, Token(tok_begin), Ready::readable(), PollOpt::edge())?;
+        self.poll.register(&client.chan.rx, Token(tok_begin + 1), Ready::readable(), PollOpt::edge())?;
+        self.poll.register(&client.rx, Token(tok_begin + 2), Ready::readable(), PollOpt::edge())?;
+        self.poll.register(&client.rrx, Token(tok_begin + 3), Ready::readable(), PollOpt::edge())?;
+
+        self.clients.insert(
+            idx,
+            client,
+        );
+
+        Ok(())
+    }
+
+    fn unregister(&mut self, idx: usize) -> bool {
+        if let Some(client) = self.clients.remove(&idx) {
+            self.poll.deregister(&client.chan.bk).unwrap();
+            self.poll.deregister(&client.chan.rx).unwrap();
+            self.poll.deregister(&client.rx).unwrap();
+            self.poll.deregister(&client.rrx).unwrap();
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn process_client(&mut self, client_idx: usize, event_idx: usize) -> Result<(), TcpClientErr> {
+        let client = match self.clients.get_mut(&client_idx) {
+            Some(x) => x,
+            None => return Err(TcpClientErr::Nx(0))
+        };
+
+        match event_idx {
+            0 => {
+                client.chan.rx_loop().map_err(|_| TcpClientErr::Rx(1000))?;
+            }
+            1 => {
+                client.chan.tx_loop().map_err(|_| TcpClientErr::Rx(1001))?;
+            }
+            2 => loop {
+                match client.rx.try_recv() {
+                    Ok(ClientApiRq::Request(idx, rq)) => {
+                        self.master_tx.send(DaemonRequest::Client(idx, rq, client.rtx.clone())).map_err(|_| TcpClientErr::Rx(108))?;
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(_) => {
+                        return Err(TcpClientErr::Rx(4));
+                    }
+                }
+            },
+            3 => loop {
+                match client.rrx.try_recv() {
+                    Ok(DaemonClient::Response(idx, rp)) => {
+                        client.tx.send(ClientApiRp::Response(idx, rp)).map_err(|_| TcpClientErr::Tx(99))?;
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(_) => {
+                        return Err(TcpClientErr::Tx(0));
+                    }
+                }
+            },
+            _ => unreachable!()
+        };
+
+        Ok(())
+    }
+
+    pub fn run(&mut self) -> Result<bool, Error> {
+        let mut events = Events::with_capacity(1024);
+
+        loop {
+            self.poll.poll(&mut events, None)?;
+
+            for event in events.iter() {
+                match event.token() {
+                    Token(0) => {
+                        loop {
+                            let (sock, _) = match self.listener.accept() {
+                                Ok(x) => x,
+                                Err(x) => match x.kind() {
+                                    io::ErrorKind::WouldBlock => break,
+                                    _ => return Err(x)
+                                }
+                            };
+
+                            sock.set_nodelay(true)?;
+                            sock.set_keepalive(Some(Duration::from_secs(1)))?;
+
+                            let (rx, tx, fw) = StreamForwarder::<TcpStream, ClientApiRq, ClientApiRp, SerdeError>::new(sock)?;
+
+                            let (rtx, rrx) = channel::<DaemonClient>();
+
+                            let client = ApiClient { chan: fw, rx, tx, rrx, rtx };
+
+                            self.register(client)?;
+                        }
+                    }
+                    Token(1) => match self.l_rcvr.try_recv() {
+                        Ok(ListenerRq::Kill) | Err(TryRecvError::Disconnected) => return Ok(false),
+                        Err(TryRecvError::Empty) => {}
+                    },
+                    Token(x) => {
+                        let client_idx = x / TOK_PER_BLOCK;
+                        let event_idx = x % TOK_PER_BLOCK;
+
+                        if self.process_client(client_idx, event_idx).is_err() {
+                            self.unregister(client_idx);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Accepts the connections of the clients, forwarding their requests to the daemon.
+pub struct TCPClientAdapter {
+    pub listener: Sender<ListenerRq>,
+}
+
+impl TCPClientAdapter {
+    pub fn new(addr: &SocketAddr, master_tx: Sender<DaemonRequest>) -> Result<Self, TCPWorkerAdapterError> {
+        let (meta_tx, meta_rx) = channel::<ListenerRq>();
+
+        let mut listener = ApiListener::new(addr, master_tx, meta_rx)?;
+
+        spawn(move || err_sink(|| listener.run()));
+
+        Ok(TCPClientAdapter {
+            listener: meta_tx,
+        })
+    }
+}
+
+impl Drop for TCPClientAdapter {
+    fn drop(&mut self) {
+        let _ = self.listener.send(ListenerRq::Kill);
+    }
+}
@@ -1,7 +1,9 @@
 pub mod tcp;
 pub mod parser;
 pub mod util;
+pub mod api;
 
 pub use tcp::*;
 pub use parser::*;
-pub use util::*;
\ No newline at end of file
+pub use util::*;
+pub use api::*;
\ No newline at end of file
@@ -1,4 +1,6 @@
-use nom::{named, map_opt, error_position, map, do_parse, take, call, le_u16};
+use nom::{named, map_opt, error_position, map, do_parse, take, call, le_u16, IResult};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 
 use crate::net::tcp::*;
 use bytes::buf::BufMut;
@@ -34,4 +36,41 @@ pub fn unparse_packet_bytes(x: &ClientBkRp) -> Result<Vec<u8>, serde_json::Error
     buf.put_u16_be(string.len() as u16);
     buf.put(string);
     Ok(buf.to_owned().to_vec())
-}
\ No newline at end of file
+}
+
+named!(
+    pub packet_bytes<&[u8]>,
+    do_parse!(
+        len: le_u16
+        >> data: take!(len)
+        >> (
+            data
+        )
+    )
+);
+
+/// Read a length-prefixed JSON packet
+pub fn read_json_packet<T: DeserializeOwned>(buffer: &[u8]) -> IResult<&[u8], T> {
+    map_opt!(buffer, packet_bytes, |x: &[u8]| serde_json::from_slice::<T>(x).ok())
+}
+
+/// Write a length-prefixed JSON packet, the counterpart of `read_json_packet`.
+///
+/// Fails if the packet does not fit the length prefix, rather than having the other side read a
+/// truncated length.
+pub fn write_json_packet<T: Serialize>(x: &T) -> Result<Vec<u8>, serde_json::Error> {
+    let string = serde_json::to_string(x)?;
+
+    if string.len() > u16::MAX as usize {
+        return Err(serde::ser::Error::custom(
+            format!("packet of {} bytes is over the limit of {}", string.len(), u16::MAX)
+        ));
+    }
+
+    let string = string.into_bytes();
+    let mut buf = bytes::BytesMut::with_capacity(string.len() + 2);
+
+    buf.put_u16_le(string.len() as u16);
+    buf.put(string);
+    Ok(buf.to_vec())
+}
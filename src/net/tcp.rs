@@ -14,7 +14,7 @@ use mio::tcp::TcpStream;
 
 use std::io::{Write, Read};
 use std::collections::HashMap;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::io::ErrorKind;
 
 use bytes;
@@ -36,7 +36,6 @@ use bytes::BigEndian;
 
 use nom::Err as NomErr;
 use std::io;
-use slab::Slab;
 use std::mem;
 
 const TA: Token = Token(0);
@@ -46,16 +45,25 @@ const TC: Token = Token(2);
 const CLIENT_CAPACITY: usize = 100;
 const CLIENT_BUFFER: usize = 65535 + 4 * 5;
 
+/// How long a worker may stay silent before it is removed, see `TCPWorkerAdapter::new`.
+pub const WORKER_DEADLINE: Duration = Duration::from_secs(30);
+
+/// The workers are pinged this many times within their deadline.
+const PINGS_PER_DEADLINE: u32 = 3;
+
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ClientBkRp {
-    Request(usize, XCmd)
+    Request(usize, XCmd),
+    /// To be answered with a `ClientBkRq::Pong` carrying the same number.
+    Ping(u64),
 }
 
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub enum ClientBkRq {
     Header(Option<usize>, Vec<CommandId>),
     Result(usize, WorkerResult),
+    Pong(u64),
 }
 
 impl StreamReadable for ClientBkRq {
@@ -90,16 +98,7 @@ impl StreamReadable for ClientBkRq {
 
 impl StreamWritable<serde_json::Error> for ClientBkRq {
     fn write(&self) -> Result<Vec<u8>, serde_json::Error> {
-        use bytes::buf::BufMut;
-
-        let string = serde_json::to_string(self)?;
-
-        let string = string.into_bytes();
-        let mut buf = bytes::BytesMut::with_capacity(string.len() + 2);
-
-        buf.put_u16_le(string.len() as u16);
-        buf.put(string);
-        Ok(buf.to_owned().to_vec())
+        write_json_packet(self)
     }
 }
 
@@ -136,16 +135,7 @@ impl StreamReadable for ClientBkRp {
 
 impl StreamWritable<serde_json::Error> for ClientBkRp {
     fn write(&self) -> Result<Vec<u8>, serde_json::Error> {
-        use bytes::buf::BufMut;
-
-        let string = serde_json::to_string(self)?;
-
-        let string = string.into_bytes();
-        let mut buf = bytes::BytesMut::with_capacity(string.len() + 2);
-
-        buf.put_u16_le(string.len() as u16);
-        buf.put(string);
-        Ok(buf.to_owned().to_vec())
+        write_json_packet(self)
     }
 }
 
@@ -286,7 +276,7 @@ StreamForwarder<S, I, O, E> {
     }
 }
 
-type AssignedCommands = Slab<(ThreadId, StepId, CommandId)>;
+type AssignedCommands = HashMap<usize, (ThreadId, StepId, CommandId)>;
 
 pub struct TcpClient {
     address: SocketAddr,
@@ -295,6 +285,8 @@ pub struct TcpClient {
     tx: Sender<ClientBkRp>,
     rrx: Receiver<DaemonWorker>,
     chan: StreamForwarder<TcpStream, ClientBkRq, ClientBkRp, SerdeError>,
+    /// When anything was last received from the worker.
+    seen: Instant,
 }
 
 pub enum ClientState {
@@ -314,6 +306,12 @@ struct Listener {
     l_rcvr: Receiver<ListenerRq>,
     tok_ctr: usize,
     clients: HashMap<usize, TcpClient>,
+    deadline: Duration,
+    pinged: Instant,
+    pings: u64,
+    /// Request ids are never reused, so that a result meant for an earlier connection is not
+    /// taken for the one of another job.
+    requests: usize,
 }
 
 #[derive(Debug)]
@@ -330,6 +328,7 @@ impl Listener {
         addr: &SocketAddr,
         master_tx: Sender<DaemonRequest>,
         l_rcvr: Receiver<ListenerRq>,
+        deadline: Duration,
     ) -> Result<Listener, Error> {
         let listener = TcpListener::bind(addr)?;
 
@@ -347,6 +346,10 @@ impl Listener {
                 l_rcvr,
                 tok_ctr: 1,
                 clients: HashMap::<usize, TcpClient>::new(),
+                deadline,
+                pinged: Instant::now(),
+                pings: 0,
+                requests: 0,
             }
         );
     }
@@ -383,6 +386,54 @@ impl Listener {
         }
     }
 
+    /// Unregister the client, its worker is removed from the daemon if it was created already.
+    fn remove(&mut self, idx: usize) -> bool {
+        let wid = match self.clients.get(&idx).map(|x| &x.state) {
+            Some(ClientState::Operating(wid, _)) => Some(wid.clone()),
+            Some(_) => None,
+            None => return false,
+        };
+
+        self.unregister(idx);
+
+        if let Some(wid) = wid {
+            let _ = self.master_tx.send(DaemonRequest::WorkerRemove(wid));
+        }
+
+        true
+    }
+
+    fn ping_every(&self) -> Duration {
+        self.deadline / PINGS_PER_DEADLINE
+    }
+
+    /// Ping the clients if it is time to, and remove the ones that have been silent for longer
+    /// than the deadline.
+    fn heartbeat(&mut self) {
+        let now = Instant::now();
+
+        if now.duration_since(self.pinged) < self.ping_every() {
+            return;
+        }
+
+        self.pinged = now;
+        self.pings = self.pings.wrapping_add(1);
+
+        let deadline = self.deadline;
+
+        let expired: Vec<usize> = self.clients.iter().filter(
+            |(_, x)| now.duration_since(x.seen) > deadline
+        ).map(|(idx, _)| *idx).collect();
+
+        for idx in expired {
+            self.remove(idx);
+        }
+
+        for client in self.clients.values() {
+            let _ = client.tx.send(ClientBkRp::Ping(self.pings));
+        }
+    }
+
     pub fn process_client(&mut self, client_idx: usize, event_idx: usize) -> Result<(), TcpClientErr> {
         let mut client = match self.clients.get_mut(&client_idx) {
             Some(x) => x,
@@ -399,6 +450,13 @@ impl Listener {
             2 => loop {
                 match client.rx.try_recv() {
                     Ok(pkt) => {
+                        client.seen = Instant::now();
+
+                        // the worker is alive whatever state it is in
+                        if let ClientBkRq::Pong(_) = pkt {
+                            continue;
+                        }
+
                         match &mut client.state {
                             ClientState::Waiting(atx) => {
                                 let atx = atx.clone();
@@ -416,9 +474,9 @@ impl Listener {
                             ClientState::Operating(wid, assigned_commands) => {
                                 match pkt {
                                     ClientBkRq::Result(idx, wres) => {
-                                        if assigned_commands.contains(idx) {
-                                            let (a, b, c) = assigned_commands.remove(idx);
-
+                                        // the ids are not reused, so a worker that reconnected can not
+                                        // answer for the jobs of its lost connection, they were requeued
+                                        if let Some((a, b, c)) = assigned_commands.remove(&idx) {
                                             self.master_tx.send(DaemonRequest::Finished(wid.clone(), a, b, c, wres)).map_err(|_| TcpClientErr::Rx(99))?;
                                         } else {
                                             return Err(TcpClientErr::Rx(1));
@@ -460,7 +518,10 @@ impl Listener {
                             ClientState::Operating(wid, acmds) => {
                                 match pkt {
                                     DaemonWorker::JobAssigned(a, b, c, d) => {
-                                        let idx = acmds.insert((a.clone(), b.clone(), c.clone()));
+                                        let idx = self.requests;
+                                        self.requests = self.requests.wrapping_add(1);
+
+                                        acmds.insert(idx, (a.clone(), b.clone(), c.clone()));
 
                                         client.tx.send(ClientBkRp::Request(idx, d)).map_err(|_| TcpClientErr::Tx(99))?;
                                     }
@@ -491,7 +552,9 @@ impl Listener {
         let mut events = Events::with_capacity(1024);
 
         loop {
-            let count = self.poll.poll(&mut events, None)?;
+            let timeout = self.ping_every().checked_sub(self.pinged.elapsed()).unwrap_or_default();
+
+            let count = self.poll.poll(&mut events, Some(timeout))?;
 
             for (evi, event) in events.iter().enumerate() {
                 match event.token() {
@@ -512,7 +575,7 @@ impl Listener {
 
                             let (atx, arx) = channel::<DaemonWorker>();
 
-                            let client = TcpClient { address, state: ClientState::Waiting(atx), chan: fw, rx, tx, rrx: arx };
+                            let client = TcpClient { address, state: ClientState::Waiting(atx), chan: fw, rx, tx, rrx: arx, seen: Instant::now() };
 
                             self.register(client).unwrap();
 
@@ -538,7 +601,7 @@ impl Listener {
 
                         match self.process_client(client_idx, event_idx) {
                             Err(x) => {
-                                let success = self.unregister(client_idx);
+                                let success = self.remove(client_idx);
                                 eprintln!("client unregistered {:?} {:?} {:?}", success, client_idx, x);
                             }
                             _ => {}
@@ -546,6 +609,8 @@ impl Listener {
                     }
                 }
             }
+
+            self.heartbeat();
         }
     }
 }
@@ -576,13 +641,15 @@ pub struct TCPWorkerAdapter {
 
 impl TCPWorkerAdapter {
     /// Should own the WorkerForwarders (they will go away with it).
-
-    pub fn new(addr: &SocketAddr, master_tx: Sender<DaemonRequest>) -> Result<Self, TCPWorkerAdapterError> {
+    ///
+    /// The workers are pinged periodically, the ones that have not sent anything for longer
+    /// than `deadline` are disconnected and removed from the daemon, so that their jobs are requeued.
+    pub fn new(addr: &SocketAddr, master_tx: Sender<DaemonRequest>, deadline: Duration) -> Result<Self, TCPWorkerAdapterError> {
         let (meta_tx, meta_rx) = channel::<ListenerRq>();
 
         // todo who owns the workers created by the ListenerThread ?
 
-        let mut listener = Listener::new(addr, master_tx, meta_rx)?;
+        let mut listener = Listener::new(addr, master_tx, meta_rx, deadline)?;
 
         let x = spawn(move || err_sink(|| listener.run()));
 
@@ -42,8 +42,9 @@ impl StreamingBuffer{
         }
     }
 
+    /// The unfilled part of the buffer
     pub fn buf(&mut self) -> &mut [u8] {
-        &mut self.b
+        &mut self.b[self.p..]
     }
 
     pub fn proceed(&mut self, size: usize) {
@@ -229,6 +230,10 @@ impl<T, O: StreamReadable> ParserStreamer<O> for T
 
         loop {
             let read = match self.read(buffer.buf()) {
+                Ok(0) => {
+                    rtn.push(Err(ParserStreamerError::from(Error::from(ErrorKind::UnexpectedEof))));
+                    break;
+                }
                 Ok(x) => x,
                 Err(err) => match err.kind() {
                     ErrorKind::WouldBlock => {
@@ -261,6 +266,7 @@ impl<T, O: StreamReadable> ParserStreamer<O> for T
                             }
                             _ => {
                                 rtn.push(Err(ParserStreamerError::from(err)));
+                                should_stop = true;
                             }
                         }
                     }
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use serde_derive::{Serialize, Deserialize};
 use crate::daemon::LOCAL_CTX;
 use crate::daemon::Op;
@@ -14,11 +14,136 @@ pub type WorkerId = GenId;
 pub type PauseId = GenId;
 
 pub type ContextIdent = GenId;
-pub type ContextValue = GenId;
 
-// todo null values for context variables
-// todo ability to access both current context and a context referred to by a variable from the current context
-// todo
+/// A value of a context variable.
+///
+/// A variable set to `Null` is different from a variable that is not set at all.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ContextValue {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Str(String),
+    Bytes(Vec<u8>),
+    List(Vec<ContextValue>),
+    Map(BTreeMap<String, ContextValue>),
+}
+
+impl ContextValue {
+    pub fn is_null(&self) -> bool {
+        *self == ContextValue::Null
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            ContextValue::Bool(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            ContextValue::Int(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            ContextValue::Str(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            ContextValue::Bytes(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&Vec<ContextValue>> {
+        match self {
+            ContextValue::List(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&BTreeMap<String, ContextValue>> {
+        match self {
+            ContextValue::Map(x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+impl From<bool> for ContextValue {
+    fn from(x: bool) -> Self {
+        ContextValue::Bool(x)
+    }
+}
+
+impl From<i64> for ContextValue {
+    fn from(x: i64) -> Self {
+        ContextValue::Int(x)
+    }
+}
+
+impl From<Vec<u8>> for ContextValue {
+    fn from(x: Vec<u8>) -> Self {
+        ContextValue::Bytes(x)
+    }
+}
+
+impl From<Vec<ContextValue>> for ContextValue {
+    fn from(x: Vec<ContextValue>) -> Self {
+        ContextValue::List(x)
+    }
+}
+
+impl From<BTreeMap<String, ContextValue>> for ContextValue {
+    fn from(x: BTreeMap<String, ContextValue>) -> Self {
+        ContextValue::Map(x)
+    }
+}
+
+/// Numbers that do not fit an `Int` are kept as strings.
+impl From<serde_json::Value> for ContextValue {
+    fn from(x: serde_json::Value) -> Self {
+        match x {
+            serde_json::Value::Null => ContextValue::Null,
+            serde_json::Value::Bool(x) => ContextValue::Bool(x),
+            serde_json::Value::Number(x) => match x.as_i64() {
+                Some(x) => ContextValue::Int(x),
+                None => ContextValue::Str(x.to_string()),
+            },
+            serde_json::Value::String(x) => ContextValue::Str(x),
+            serde_json::Value::Array(x) => ContextValue::List(x.into_iter().map(ContextValue::from).collect()),
+            serde_json::Value::Object(x) => ContextValue::Map(x.into_iter().map(|(k, v)| (k, v.into())).collect()),
+        }
+    }
+}
+
+impl From<String> for ContextValue {
+    fn from(x: String) -> Self {
+        ContextValue::Str(x)
+    }
+}
+
+impl From<&str> for ContextValue {
+    fn from(x: &str) -> Self {
+        ContextValue::Str(x.to_string())
+    }
+}
+
+impl From<Option<String>> for ContextValue {
+    fn from(x: Option<String>) -> Self {
+        match x {
+            Some(x) => ContextValue::Str(x),
+            None => ContextValue::Null,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct WorkerStatus {
@@ -26,13 +151,18 @@ pub struct WorkerStatus {
     queues: Vec<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Ctx {
     pub(crate) id: ContextId,
     pub(crate) vals: HashMap<ContextIdent, ContextValue>,
 }
 
 impl Ctx {
+    pub fn id(&self) -> &ContextId {
+        &self.id
+    }
+
+    /// `None` if the variable is not set, as opposed to being set to `ContextValue::Null`.
     pub fn get(&self, ident: &ContextIdent) -> Option<ContextValue> {
         match self.vals.get(ident) {
             Some(x) => Some(x.clone()),
@@ -44,7 +174,13 @@ impl Ctx {
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum CtxNs {
     Curr,
-    Ref(ContextIdent),
+    /// the variables holding the ids of the contexts to walk through: the first one is read from
+    /// the current context, every next one from the context named by the previous one.
+    ///
+    /// A variable holding a map is walked into instead, so that `$^exc.kind` is the `kind` of it.
+    Ref(Vec<ContextIdent>),
+    /// the current context, then the contexts of the callers linked by `^ctx`
+    Lexical,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -82,14 +218,34 @@ pub struct XCtxRef(
 );
 
 impl XCtxRef {
+    fn ctx(&self) -> RValueLocal {
+        match &self.0 {
+            XCtxNs::Curr => RValueLocal::Ref(LOCAL_CTX.to_string()),
+            XCtxNs::Ref(id) => RValueLocal::Const(id.clone().into()),
+        }
+    }
+
     pub fn set(&self, val: RValueLocal) -> Op {
-        let XCtxRef(ns, var) = &self;
         Op::ContextSet(
-            match ns {
-                XCtxNs::Curr => RValueLocal::Ref(LOCAL_CTX.to_string()),
-                XCtxNs::Ref(id) => RValueLocal::Const(id.clone()),
-            },
-            RValueLocal::Const(var.clone()),
+            self.ctx(),
+            RValueLocal::Const(self.1.clone().into()),
+            val,
+        )
+    }
+
+    pub fn append(&self, val: RValueLocal) -> Op {
+        Op::ListAppend(
+            self.ctx(),
+            RValueLocal::Const(self.1.clone().into()),
+            val,
+        )
+    }
+
+    pub fn compare_and_set(&self, expected: Option<RValueLocal>, val: RValueLocal) -> Op {
+        Op::ContextCompareAndSet(
+            self.ctx(),
+            RValueLocal::Const(self.1.clone().into()),
+            expected,
             val,
         )
     }
@@ -98,14 +254,15 @@ impl XCtxRef {
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct XCmd {
     pub id: CommandId,
-    pub opcode: ContextValue,
+    /// the queue the command is put in
+    pub opcode: CommandId,
     pub args: Vec<XCmdArg>,
 }
 
 impl XCmd {
     pub fn create(
         id: CommandId,
-        opcode: ContextValue,
+        opcode: CommandId,
         args: Vec<XCmdArg>,
     ) -> Self {
         XCmd {
@@ -119,6 +276,7 @@ impl XCmd {
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum XCmdArg {
     Const(ContextValue),
+    /// the value is `None` if the variable is not set
     Ref(XCtxRef, Option<ContextValue>),
 }
 
@@ -156,11 +314,12 @@ impl Ctx {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum InterpolationError {
     CtxNull,
     CtxMiss(ContextId),
     CmdNull,
+    /// the variable does not hold the id of a context, or the key is missing from the map
     Ref(CtxRef),
 }
 
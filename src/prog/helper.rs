@@ -4,7 +4,7 @@ pub use std::slice::*;
 pub use crate::prog::ir_loader::*;
 pub use crate::prog::parser::{located_span_map, located_span_map_res, Input};
 use std::str::Utf8Error;
-use std::cmp::max;
+use std::cmp::min;
 use std::fmt::Debug;
 
 fn build_offsets(items: &Vec<&str>) -> Vec<usize> {
@@ -52,7 +52,7 @@ pub fn format_error<EP: Debug>(input: &Input, err: &IRErr<EP>) -> Result<String,
     let post = 3;
 
     let idx_start = matching_idx.saturating_sub(pre);
-    let idx_end = max(items.len(), matching_idx.saturating_add(post));
+    let idx_end = min(items.len(), matching_idx.saturating_add(post + 1));
 
     let mut ret: Vec<String> = Vec::<String>::with_capacity(idx_end - idx_start + 1);
 
@@ -113,9 +113,10 @@ pub fn ir_load<'a, 'b, EP>(file: IResult<Input<'a>, IRFile, EP>) -> Result<IRMap
         match &item.item {
             IRLine::Command(key, args) => {
                 let map_param = |x: &IRArg| match x {
-                    IRArg::Const(z) => CmdArg::Const(z.clone()),
+                    IRArg::Const(z) => CmdArg::Const(z.clone().into()),
                     IRArg::Ref(z) => CmdArg::Ref(CtxRef(CtxNs::Curr, z.clone())),
-                    IRArg::XRef(a, z) => CmdArg::Ref(CtxRef(CtxNs::Ref(a.clone()), z.clone())),
+                    IRArg::XRef(path, z) => CmdArg::Ref(CtxRef(CtxNs::Ref(path.clone()), z.clone())),
+                    IRArg::LexRef(z) => CmdArg::Ref(CtxRef(CtxNs::Lexical, z.clone())),
                 };
 
                 let opcode = args.first();
@@ -75,23 +75,52 @@ pub fn coderef(input: Input) -> IResult<Input, StrOutput> {
     )
 }
 
+/// An identifier of a variable, the ones prefixed with `^` are set up by the daemon.
+pub fn varname(input: Input) -> IResult<Input, StrOutput> {
+    map_res!(
+        input,
+        recognize!(
+            pair!(
+                opt!(complete!(tag!("^"))),
+                identifier
+            )
+        ),
+        |x| located_span_map_res(x, str::from_utf8)
+    )
+}
+
 pub fn ctxref(input: Input) -> IResult<Input, StrOutput> {
     do_parse!(
         input,
         tag!("$") >>
-        id: identifier >>
+        id: varname >>
+        (id)
+    )
+}
+
+/// `$*a`: the variable as set in the closest frame.
+pub fn ctxlexref(input: Input) -> IResult<Input, StrOutput> {
+    do_parse!(
+        input,
+        tag!("$*") >>
+        id: varname >>
         (id)
     )
 }
 
-pub fn ctxxref(input: Input) -> IResult<Input, (StrOutput, StrOutput)> {
+/// `$a.b.c`: the variables the contexts are referred to by, followed by the variable itself.
+pub fn ctxxref(input: Input) -> IResult<Input, (Vec<StrOutput>, StrOutput)> {
     do_parse!(
         input,
         tag!("$") >>
-        xref: identifier >>
-        tag!(".") >>
-        id: identifier >>
-        ( (xref, id) )
+        first: varname >>
+        rest: many1!(complete!(preceded!(tag!("."), varname))) >>
+        ( {
+            let mut path = vec![first];
+            path.extend(rest);
+            let id = path.pop().unwrap();
+            (path, id)
+        } )
     )
 }
 
@@ -158,9 +187,10 @@ pub fn ir_arg(input: Input) -> IResult<Input, Located<IRArg>> {
     alt_complete!( input,
         string => { |x| Located::from_span(x).map(|x| IRArg::Const(String::from(x))) } |
         ctxxref => {
-        |(x, y): (LocatedSpan<&str>, LocatedSpan<&str>)|
-        Located::from_span(x).with_val((x.fragment, y.fragment)).map(|(x, y)| IRArg::XRef(String::from(x), String::from(y)))
+        |(x, y): (Vec<LocatedSpan<&str>>, LocatedSpan<&str>)|
+        Located::from_span(x[0]).with_val(IRArg::XRef(x.iter().map(|x| String::from(x.fragment)).collect(), String::from(y.fragment)))
         } |
+        ctxlexref => { |x| Located::from_span(x).map(|x| IRArg::LexRef(String::from(x))) } |
         ctxref => { |x| Located::from_span(x).map(|x| IRArg::Ref(String::from(x))) }
 
     )
@@ -283,7 +313,8 @@ impl <T>Located<T> {
 pub enum IRArg {
     Const(String),
     Ref(String),
-    XRef(String, String),
+    XRef(Vec<String>, String),
+    LexRef(String),
 }
 
 #[derive(Debug, Clone)]
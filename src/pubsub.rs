@@ -4,7 +4,13 @@ use std::collections::HashSet;
 use std::hash::Hash;
 use std::collections::VecDeque;
 
+use rand::seq::IteratorRandom;
+use rand::thread_rng;
+use serde_derive::{Serialize, Deserialize};
+
 static DEFAULT_WORKER_CAPACITY: usize = 5;
+/// milliseconds, see `QueueLimit::rate`
+static RATE_WINDOW: u64 = 1000;
 
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
 pub(crate) struct PubSubJob<QK: Clone + Eq + Hash + PartialEq, JK: Clone + Eq + Hash + PartialEq> {
@@ -20,11 +26,34 @@ pub(crate) struct PubSubWorkerInfo<WK, QK: Clone + Eq + Hash, JK: Clone + Eq + H
     pub(crate) queues: Vec<QK>,
 }
 
+/// How `PubSub::assign` picks among the workers of a queue that can take another job.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Selection {
+    /// each worker in turn, in the order they were added
+    RoundRobin,
+    /// the worker with the smallest share of its capacity taken, workers without a capacity
+    /// counting as empty, then the one with the fewest jobs
+    LeastLoaded,
+    Random,
+}
+
+impl Default for Selection {
+    fn default() -> Self {
+        Selection::LeastLoaded
+    }
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct PubSub<WK: Clone + Eq + Hash, QK: Clone + Eq + Hash, JK: Clone + Eq + Hash> {
     pub(crate) workers: HashMap<WK, PubSubWorkerInfo<WK, QK, JK>>,
+    /// the workers of the queue that can take another job
     pub(crate) queues_workers: HashMap<QK, HashSet<WK>>,
     pub(crate) jobs_workers: HashMap<JK, WK>,
+    /// every worker of the queue in the order they were added, see `Selection::RoundRobin`
+    pub(crate) queues_rings: HashMap<QK, Vec<WK>>,
+    /// the index in the ring to start looking for the next worker from
+    pub(crate) queues_cursors: HashMap<QK, usize>,
+    pub(crate) queues_selections: HashMap<QK, Selection>,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -35,11 +64,49 @@ pub(crate) struct Assignment<WK: Clone + Eq + Hash, QK: Clone + Eq + Hash, JK: C
     pub(crate) job_key: JK,
 }
 
+/// The share of a queue in the jobs handed out to the workers subscribed to several queues.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QueueWeight {
+    /// the pending jobs of the queues with a higher priority are handed out first
+    pub priority: i32,
+    /// among the queues of the same priority, the jobs are taken in proportion to the weights
+    pub weight: usize,
+}
+
+impl Default for QueueWeight {
+    fn default() -> Self {
+        QueueWeight { priority: 0, weight: 1 }
+    }
+}
+
+/// The limits of a queue across all of its workers, the jobs are held pending while it is over them.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct QueueLimit {
+    /// the most jobs of the queue assigned at once
+    pub concurrency: Option<usize>,
+    /// the most jobs of the queue assigned within a second, see `MultiQueue::tick`
+    pub rate: Option<usize>,
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct MultiQueue<WK: Clone + Eq + Hash, QK: Clone + Eq + Hash, JK: Clone + Eq + Hash> {
+    /// the pending jobs, the ones with a higher priority first
     pub(crate) queues: HashMap<QK, VecDeque<JK>>,
     pub(crate) pubsub: PubSub<WK, QK, JK>,
     pub(crate) worker_queues: HashMap<WK, Vec<QK>>,
+    pub(crate) queues_weights: HashMap<QK, QueueWeight>,
+    /// the credit of the queue in the weighted round-robin, see `MultiQueue::queue_next`
+    pub(crate) queues_credits: HashMap<QK, i64>,
+    /// the priorities of the jobs other than 0, until they are finished
+    pub(crate) jobs_priorities: HashMap<JK, i32>,
+    pub(crate) queues_limits: HashMap<QK, QueueLimit>,
+    /// the jobs of the queue that are assigned and not finished yet
+    pub(crate) queues_running: HashMap<QK, usize>,
+    /// when the jobs of the limited queue were assigned, within the last second
+    pub(crate) queues_started: HashMap<QK, VecDeque<u64>>,
+    /// milliseconds since the UNIX epoch, as of the last `MultiQueue::tick`
+    pub(crate) now: u64,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -67,6 +134,9 @@ impl<WK: Clone + Eq + Hash, QK: Clone + Eq + Hash, JK: Clone + Eq + Hash> Defaul
             workers: HashMap::<WK, PubSubWorkerInfo<WK, QK, JK>>::default(),
             queues_workers: HashMap::<QK, HashSet<WK>>::default(),
             jobs_workers: HashMap::<JK, WK>::default(),
+            queues_rings: HashMap::<QK, Vec<WK>>::default(),
+            queues_cursors: HashMap::<QK, usize>::default(),
+            queues_selections: HashMap::<QK, Selection>::default(),
         }
     }
 }
@@ -86,6 +156,13 @@ impl<WK: Clone + Eq + Hash, QK: Clone + Eq + Hash, JK: Clone + Eq + Hash> Defaul
             queues: HashMap::<QK, VecDeque<JK>>::default(),
             pubsub: PubSub::<WK, QK, JK>::default(),
             worker_queues: HashMap::<WK, Vec<QK>>::default(),
+            queues_weights: HashMap::<QK, QueueWeight>::default(),
+            queues_credits: HashMap::<QK, i64>::default(),
+            jobs_priorities: HashMap::<JK, i32>::default(),
+            queues_limits: HashMap::<QK, QueueLimit>::default(),
+            queues_running: HashMap::<QK, usize>::default(),
+            queues_started: HashMap::<QK, VecDeque<u64>>::default(),
+            now: 0,
         }
     }
 }
@@ -97,6 +174,10 @@ impl<WK: Clone + Eq + Hash, QK: Clone + Eq + Hash, JK: Clone + Eq + Hash> PubSub
 
         self.workers.insert(worker.key.clone(), worker.clone());
 
+        for queue_key in queues {
+            self.queues_rings.entry(queue_key.clone()).or_default().push(worker.key.clone());
+        }
+
         if worker.ready() {
             self.worker_enable(&worker.key)
         }
@@ -126,6 +207,15 @@ impl<WK: Clone + Eq + Hash, QK: Clone + Eq + Hash, JK: Clone + Eq + Hash> PubSub
             if to_remove {
                 self.queues_workers.remove(queue_key);
             }
+
+            if let Some(ring) = self.queues_rings.get_mut(queue_key) {
+                ring.retain(|x| *x != val.key);
+
+                if ring.is_empty() {
+                    self.queues_rings.remove(queue_key);
+                    self.queues_cursors.remove(queue_key);
+                }
+            }
         }
 
         Some(val.current.iter().map(|x| (x.qk.clone(), x.jk.clone())).collect())
@@ -161,12 +251,50 @@ impl<WK: Clone + Eq + Hash, QK: Clone + Eq + Hash, JK: Clone + Eq + Hash> PubSub
         }
     }
 
+    /// Pick the worker to assign the next job of the queue to, see `Selection`.
+    pub fn select(&mut self, key: &QK) -> Option<WK> {
+        let ready = self.queues_workers.get(key)?;
+
+        match self.queues_selections.get(key).cloned().unwrap_or_default() {
+            Selection::RoundRobin => {
+                let ring = self.queues_rings.get(key)?;
+                let cursor = self.queues_cursors.entry(key.clone()).or_insert(0);
+
+                for offset in 0..ring.len() {
+                    let idx = (*cursor + offset) % ring.len();
+
+                    if ready.contains(&ring[idx]) {
+                        *cursor = idx + 1;
+                        return Some(ring[idx].clone());
+                    }
+                }
+
+                None
+            }
+            Selection::LeastLoaded => {
+                let workers = &self.workers;
+
+                let load = |key: &WK| {
+                    let worker = workers.get(key).unwrap();
+
+                    let share = match worker.capacity {
+                        Some(capacity) if capacity > 0 => worker.current.len() as f64 / capacity as f64,
+                        _ => 0.,
+                    };
+
+                    (share, worker.current.len())
+                };
+
+                ready.iter().min_by(|a, b| load(a).partial_cmp(&load(b)).unwrap()).cloned()
+            }
+            Selection::Random => {
+                ready.iter().choose(&mut thread_rng()).cloned()
+            }
+        }
+    }
+
     pub fn assign(&mut self, key: &QK, job_key: &JK) -> Option<WK> {
-        let worker_id = match self.queues_workers.get(key) {
-            // todo current implementation is incredibly greedy towards the first element
-            Some(workers) => Some(workers.iter().nth(0).unwrap().clone()),
-            None => None
-        };
+        let worker_id = self.select(key);
 
         match worker_id {
             Some(worker_id) => {
@@ -215,8 +343,20 @@ impl<WK: Clone + Eq + Hash, QK: Clone + Eq + Hash, JK: Clone + Eq + Hash> MultiQ
         Vec::<Assignment<WK, QK, JK>>::with_capacity(capacity)
     }
 
-    pub fn job_create(&mut self, queue_key: &QK, job_key: &JK) -> Vec<Assignment<WK, QK, JK>> {
-        match self.pubsub.assign(queue_key, job_key) {
+    /// Create a job that is handed out before the pending jobs of the queue with a lower priority.
+    pub fn job_create(&mut self, queue_key: &QK, job_key: &JK, priority: i32) -> Vec<Assignment<WK, QK, JK>> {
+        if priority != 0 {
+            self.jobs_priorities.insert(job_key.clone(), priority);
+        }
+
+        // the job takes its turn behind the pending ones, by its priority
+        if self.queues.get(queue_key).map_or(false, |x| !x.is_empty()) {
+            self.job_pending(queue_key, job_key);
+
+            return self.assign_queues(&vec![queue_key.clone()], None);
+        }
+
+        match self.assign(queue_key, job_key) {
             Some(worker_key) => vec![Assignment::new(
                 Action::Started, worker_key,
                 queue_key.clone(),
@@ -231,8 +371,12 @@ impl<WK: Clone + Eq + Hash, QK: Clone + Eq + Hash, JK: Clone + Eq + Hash> MultiQ
     }
 
     pub fn job_finish(&mut self, queue_key: &QK, job_key: &JK) -> Vec<Assignment<WK, QK, JK>> {
+        self.jobs_priorities.remove(job_key);
+
         match self.pubsub.resign(queue_key, job_key) {
             Some(worker_key) => {
+                self.queue_finished(queue_key);
+
                 let mut assignment = Self::assignment(2);
 
 
@@ -273,6 +417,124 @@ impl<WK: Clone + Eq + Hash, QK: Clone + Eq + Hash, JK: Clone + Eq + Hash> MultiQ
         }
     }
 
+    /// Set the priority and the weight of the queue, see `QueueWeight`.
+    pub fn queue_weight(&mut self, queue_key: QK, weight: QueueWeight) {
+        self.queues_weights.insert(queue_key, weight);
+    }
+
+    /// Set the limits of the queue, see `QueueLimit`.
+    pub fn queue_limit(&mut self, queue_key: QK, limit: QueueLimit) {
+        self.queues_limits.insert(queue_key, limit);
+    }
+
+    /// Advance the clock of the rate limits to `now`, assigning the jobs held back by them.
+    pub fn tick(&mut self, now: u64) -> Vec<Assignment<WK, QK, JK>> {
+        self.now = now;
+
+        for started in self.queues_started.values_mut() {
+            while started.front().map_or(false, |x| x + RATE_WINDOW <= now) {
+                started.pop_front();
+            }
+        }
+
+        let queue_keys: Vec<QK> = self.queues_limits.keys().filter(
+            |x| self.queues.get(x).map_or(false, |x| !x.is_empty())
+        ).cloned().collect();
+
+        let mut assignment = Self::assignment(queue_keys.len());
+
+        for queue_key in queue_keys {
+            while let Some(job_key) = self.queues.get(&queue_key).and_then(|x| x.front()).cloned() {
+                match self.assign(&queue_key, &job_key) {
+                    Some(worker_key) => {
+                        self.queues.get_mut(&queue_key).unwrap().pop_front();
+
+                        assignment.push(Assignment::new(Action::Started, worker_key, queue_key.clone(), job_key));
+                    }
+                    None => break
+                }
+            }
+        }
+
+        assignment
+    }
+
+    /// When a job held back by a rate limit can be assigned next, see `MultiQueue::tick`.
+    pub fn next_release(&self) -> Option<u64> {
+        self.queues_limits.iter().filter_map(|(queue_key, limit)| {
+            let rate = limit.rate.filter(|x| *x > 0)?;
+
+            if self.queues.get(queue_key).map_or(true, |x| x.is_empty()) {
+                return None;
+            }
+
+            let started = self.queues_started.get(queue_key)?;
+
+            match started.len() >= rate {
+                true => Some(started[started.len() - rate] + RATE_WINDOW),
+                false => None,
+            }
+        }).min()
+    }
+
+    /// Whether a job of the queue can be assigned without going over its limits.
+    fn queue_open(&self, queue_key: &QK) -> bool {
+        let limit = match self.queues_limits.get(queue_key) {
+            Some(x) => x,
+            None => return true
+        };
+
+        if let Some(concurrency) = limit.concurrency {
+            if self.queues_running.get(queue_key).cloned().unwrap_or(0) >= concurrency {
+                return false;
+            }
+        }
+
+        if let Some(rate) = limit.rate {
+            let now = self.now;
+
+            let started = self.queues_started.get(queue_key).map_or(
+                0,
+                |x| x.iter().filter(|x| **x + RATE_WINDOW > now).count(),
+            );
+
+            if started >= rate {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    fn queue_finished(&mut self, queue_key: &QK) {
+        if let Some(running) = self.queues_running.get_mut(queue_key) {
+            *running = running.saturating_sub(1);
+        }
+    }
+
+    /// Assign the job to a worker of the queue, unless the queue is over its limits.
+    fn assign(&mut self, queue_key: &QK, job_key: &JK) -> Option<WK> {
+        if !self.queue_open(queue_key) {
+            return None;
+        }
+
+        let worker_key = self.pubsub.assign(queue_key, job_key)?;
+
+        // counted for every queue, so that a limit set later holds for the jobs running already
+        *self.queues_running.entry(queue_key.clone()).or_insert(0) += 1;
+
+        if self.queues_limits.contains_key(queue_key) {
+            self.queues_started.entry(queue_key.clone()).or_default().push_back(self.now);
+        }
+
+        Some(worker_key)
+    }
+
+    /// Set how the workers of the queue are picked from now on.
+    pub fn queue_selection(&mut self, queue_key: QK, selection: Selection) {
+        self.pubsub.queues_selections.insert(queue_key, selection);
+    }
+
     pub fn worker_add(&mut self, key: WK, capacity: Option<usize>, queues: &Vec<QK>) -> Vec<Assignment<WK, QK, JK>> {
         match self.worker_queues.get(&key) {
             Some(_x) => panic!("worker already exists"),
@@ -286,87 +548,122 @@ impl<WK: Clone + Eq + Hash, QK: Clone + Eq + Hash, JK: Clone + Eq + Hash> MultiQ
         self.assign_queues(&queues, capacity)
     }
 
+    /// Remove the worker, its jobs are `Cancelled` and handed out to the other workers of their
+    /// queues as they have the capacity for them.
     pub fn worker_remove(&mut self, key: &WK) -> Vec<Assignment<WK, QK, JK>> {
         let reassigned = self.pubsub.remove(key).unwrap();
 
-        self.worker_queues.remove(&key);
+        let queues = self.worker_queues.remove(&key).unwrap_or_default();
 
         for (qk, jk) in reassigned.iter() {
-            self.job_pending(&qk, &jk);
+            self.queue_finished(qk);
+            self.job_pending(qk, jk);
         }
 
-        reassigned.iter().map(|(qk, jk)| Assignment::new(
+        let mut assignment: Vec<_> = reassigned.iter().map(|(qk, jk)| Assignment::new(
             Action::Cancelled, key.clone(), qk.clone(), jk.clone())
-        ).collect()
+        ).collect();
+
+        if !reassigned.is_empty() {
+            assignment.append(&mut self.assign_queues(&queues, Some(reassigned.len())));
+        }
+
+        assignment
     }
 
     fn job_pending(&mut self, queue_key: &QK, job_key: &JK) {
+        let priority = self.job_priority(job_key);
+
+        let jobs_priorities = &self.jobs_priorities;
         let entry = self.queues.entry(queue_key.clone()).or_insert_with(|| VecDeque::<JK>::default());
-        entry.push_back(job_key.clone());
+
+        // behind the jobs of the same priority
+        let index = entry.iter().position(
+            |x| jobs_priorities.get(x).cloned().unwrap_or(0) < priority
+        ).unwrap_or(entry.len());
+
+        entry.insert(index, job_key.clone());
     }
 
-    fn assign_queues(&mut self, queues: &Vec<QK>, capacity: Option<usize>) -> Vec<Assignment<WK, QK, JK>> {
-        let mut capacity = capacity.clone();
+    fn job_priority(&self, job_key: &JK) -> i32 {
+        self.jobs_priorities.get(job_key).cloned().unwrap_or(0)
+    }
 
-        let mut assignment = Self::assignment(capacity.unwrap_or(DEFAULT_WORKER_CAPACITY));
+    /// Pick the queue to take the next pending job from: the ones with the highest priority first,
+    /// then a smooth weighted round-robin among them.
+    ///
+    /// Every queue gains its weight in credit at each pick, the one with the most credit is picked and
+    /// pays back the total weight, so the picks are spread in proportion to the weights. The credits
+    /// after the pick are returned along with the queue, to be kept once its job is assigned.
+    fn queue_next(&self, queues: &[QK]) -> Option<(QK, Vec<(QK, i64)>)> {
+        let pending: Vec<(&QK, QueueWeight)> = queues.iter().filter(
+            |x| self.queues.get(x).map_or(false, |x| !x.is_empty()) && self.queue_open(x)
+        ).map(
+            |x| (x, self.queues_weights.get(x).cloned().unwrap_or_default())
+        ).collect();
 
-        let check_capacity = |capacity: Option<usize>| match capacity {
-            Some(x) => x > 0,
-            None => true
-        };
+        let priority = pending.iter().map(|(_, x)| x.priority).max()?;
+
+        let candidates: Vec<(&QK, QueueWeight)> = pending.into_iter().filter(|(_, x)| x.priority == priority).collect();
+
+        let total: i64 = candidates.iter().map(|(_, x)| x.weight as i64).sum();
+
+        let mut credits: Vec<(QK, i64)> = candidates.into_iter().map(|(queue_key, weight)| {
+            let credit = self.queues_credits.get(queue_key).cloned().unwrap_or(0);
+
+            (queue_key.clone(), credit + weight.weight as i64)
+        }).collect();
+
+        let mut picked = 0;
 
-        let mut queues_iter = queues.iter();
+        for (i, (_, credit)) in credits.iter().enumerate() {
+            if *credit > credits[picked].1 {
+                picked = i;
+            }
+        }
+
+        credits[picked].1 -= total;
+
+        Some((credits[picked].0.clone(), credits))
+    }
+
+    fn assign_queues(&mut self, queues: &Vec<QK>, capacity: Option<usize>) -> Vec<Assignment<WK, QK, JK>> {
+        let mut capacity = capacity;
+        let mut queues = queues.clone();
+
+        let mut assignment = Self::assignment(capacity.unwrap_or(DEFAULT_WORKER_CAPACITY));
 
-        while check_capacity(capacity) {
-            let queue_key = match queues_iter.next() {
+        while capacity != Some(0) {
+            let (queue_key, credits) = match self.queue_next(&queues) {
                 Some(x) => x,
                 None => break
             };
 
-            let queue = match self.queues.get_mut(queue_key) {
-                Some(x) => x,
-                None => continue
-            };
+            let job_key = self.queues.get(&queue_key).and_then(|x| x.front()).unwrap().clone();
 
-            while check_capacity(capacity) {
-                let job_key = match queue.pop_front() {
-                    Some(x) => x,
-                    None => break
-                };
+            match self.assign(&queue_key, &job_key) {
+                Some(worker_key) => {
+                    self.queues.get_mut(&queue_key).unwrap().pop_front();
 
-                match self.pubsub.assign(&queue_key, &job_key) {
-                    Some(worker_key) => {
-                        assignment.push(
-                            Assignment::new(
-                                Action::Started,
-                                worker_key.clone(),
-                                queue_key.clone(),
-                                job_key.clone(),
-                            )
-                        );
-
-                        match capacity {
-                            Some(x) => {
-                                capacity = Some(x - 1)
-                            }
-                            None => {
-                                continue;
-                            }
-                        }
-                    }
-                    None => {
-                        match capacity {
-                            Some(_) => {
-                                capacity = Some(0)
-                            }
-                            None => {
-                                continue;
-                            }
-                        }
-                    }
+                    // the queues only pay for the picks that are assigned
+                    self.queues_credits.extend(credits);
+
+                    assignment.push(
+                        Assignment::new(
+                            Action::Started,
+                            worker_key,
+                            queue_key,
+                            job_key,
+                        )
+                    );
+
+                    capacity = capacity.map(|x| x - 1);
                 }
+                // the job stays on the queue until a worker is ready for it, the other queues
+                // might have one ready still
+                None => queues.retain(|x| x != &queue_key),
             }
-        };
+        }
 
         assignment
     }
@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+use mio_extras::channel::channel;
+
+use crate::builtin::*;
+use crate::daemon::*;
+use crate::obj::*;
+use crate::tests::prog::*;
+use crate::worker::*;
+
+pub(crate) static TEST_COUNT: &str = "./etc/ir/count.ir";
+pub(crate) static TEST_LOCK: &str = "./etc/ir/lock.ir";
+pub(crate) static TEST_CALL: &str = "./etc/ir/call.ir";
+pub(crate) static TEST_APPROVAL: &str = "./etc/ir/approval.ir";
+pub(crate) static TEST_SLEEP: &str = "./etc/ir/sleep.ir";
+
+#[test]
+fn test_builtin_count() {
+    let ir = LoadIRFile::new(TEST_COUNT);
+    let ir = ir.load().unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let mut dpu = DPU::default();
+    dpu.get_state_mut().insert_commands(ir.iter());
+
+    let mut worker = LocalWorker::new(BuiltinWorker::default(), tx);
+
+    let (thread_id, _) = dpu.thread_start("ep".into(), None);
+
+    for _ in 0..100 {
+        dpu.process(&rx).unwrap();
+        worker.run();
+    }
+
+    assert_eq!(
+        dpu.thread_status(&thread_id),
+        Some(ThreadStatus::Exited(Ok(()))),
+    );
+
+    let ctx = dpu.get_state_mut().threads.get(&thread_id).unwrap().ctx.clone().unwrap();
+
+    assert_eq!(
+        dpu.context(&ctx).unwrap().get(&"i".into()),
+        Some(ContextValue::Int(3)),
+    );
+}
+
+#[test]
+fn test_builtin_unknown_opcode() {
+    let cmd = XCmd::create("0".into(), "db_user_list".into(), vec![]);
+
+    assert_eq!(
+        BuiltinWorker::default().exec(&cmd),
+        Err(WorkerErr::Default(OpErrReason::UnknownOp)),
+    );
+}
+
+#[test]
+fn test_builtin_list_get() {
+    let cmd = XCmd::create("0".into(), "list_get".into(), vec![
+        XCmdArg::Ref(XCtxRef(XCtxNs::Curr, "users".into()), Some(ContextValue::List(vec!["a".into(), "b".into(), "c".into()]))),
+        XCmdArg::Const("1".into()),
+        // the destination may be in another context than the current one
+        XCmdArg::Ref(XCtxRef(XCtxNs::Ref("parent".into()), "user".into()), None),
+        XCmdArg::Const("1".into()),
+    ]);
+
+    assert_eq!(
+        BuiltinWorker::default().exec(&cmd),
+        Ok(vec![
+            Op::ContextSet(
+                RValueLocal::Const("parent".into()),
+                RValueLocal::Const("user".into()),
+                RValueLocal::Const("b".into()),
+            ),
+            Op::LocalSet(
+                LOCAL_NIP.into(),
+                RValue::Local(RValueLocal::Const("1".into())),
+            ),
+        ]),
+    );
+}
+
+#[test]
+fn test_builtin_iadd_overflow() {
+    let cmd = XCmd::create("0".into(), "iadd".into(), vec![
+        XCmdArg::Ref(XCtxRef(XCtxNs::Curr, "i".into()), Some(ContextValue::Int(i64::MAX))),
+        XCmdArg::Const("1".into()),
+        XCmdArg::Const("1".into()),
+    ]);
+
+    assert_eq!(
+        BuiltinWorker::default().exec(&cmd),
+        Err(WorkerErr::Default(OpErrReason::InvalidArg(1))),
+    );
+}
+
+#[test]
+fn test_builtin_exit() {
+    let cmd = XCmd::create("0".into(), "exit".into(), vec![]);
+
+    assert_eq!(
+        BuiltinWorker::default().exec(&cmd),
+        Ok(vec![Op::ThreadExit]),
+    );
+}
+
+#[test]
+fn test_builtin_cas_lock() {
+    let ir = LoadIRFile::new(TEST_LOCK);
+    let ir = ir.load().unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let mut dpu = DPU::default();
+    dpu.get_state_mut().insert_commands(ir.iter());
+    dpu.get_state_mut().insert_context(&Ctx::create("lock".into(), HashMap::new()));
+
+    // every thread refers to the shared context from its own
+    for id in &["a", "b"] {
+        let vals = vec![("lock".to_string(), ContextValue::from("lock"))].into_iter().collect();
+        dpu.get_state_mut().insert_context(&Ctx::create(id.to_string(), vals));
+    }
+
+    let mut worker = LocalWorker::new(BuiltinWorker::default(), tx);
+
+    let (_, a_exit) = dpu.thread_start("ep".into(), Some("a".into()));
+    let (_, b_exit) = dpu.thread_start("ep".into(), Some("b".into()));
+
+    for _ in 0..100 {
+        dpu.process(&rx).unwrap();
+        worker.run();
+    }
+
+    let mut results = [a_exit.try_recv().unwrap().result, b_exit.try_recv().unwrap().result];
+    results.sort_by_key(|x| x.is_err());
+
+    assert_eq!(results[0], Ok(()));
+
+    match &results[1] {
+        Err(ThreadError::WorkerPost(err)) => assert_eq!(
+            err.op_reason,
+            OpErrReason::CompareFailed { ident: "held".into(), expected: None, found: Some("true".into()) },
+        ),
+        x => panic!("{:?}", x),
+    }
+
+    assert_eq!(dpu.context(&"lock".into()).unwrap().get(&"held".into()), Some("true".into()));
+}
+
+#[test]
+fn test_builtin_null() {
+    let cmd = XCmd::create("0".into(), "null".into(), vec![
+        XCmdArg::Ref(XCtxRef(XCtxNs::Curr, "user".into()), None),
+        XCmdArg::Const("1".into()),
+    ]);
+
+    assert_eq!(
+        BuiltinWorker::default().exec(&cmd),
+        Ok(vec![
+            Op::ContextSet(
+                RValueLocal::Ref(LOCAL_CTX.into()),
+                RValueLocal::Const("user".into()),
+                RValueLocal::Const(ContextValue::Null),
+            ),
+            Op::LocalSet(
+                LOCAL_NIP.into(),
+                RValue::Local(RValueLocal::Const("1".into())),
+            ),
+        ]),
+    );
+}
+
+#[test]
+fn test_builtin_list_append() {
+    let cmd = XCmd::create("0".into(), "list_append".into(), vec![
+        XCmdArg::Ref(XCtxRef(XCtxNs::Curr, "users".into()), Some(ContextValue::List(vec![]))),
+        XCmdArg::Const("a".into()),
+        XCmdArg::Const("1".into()),
+    ]);
+
+    assert_eq!(
+        BuiltinWorker::default().exec(&cmd),
+        Ok(vec![
+            Op::ListAppend(
+                RValueLocal::Ref(LOCAL_CTX.into()),
+                RValueLocal::Const("users".into()),
+                RValueLocal::Const("a".into()),
+            ),
+            Op::LocalSet(
+                LOCAL_NIP.into(),
+                RValue::Local(RValueLocal::Const("1".into())),
+            ),
+        ]),
+    );
+}
+
+#[test]
+fn test_builtin_call_ret() {
+    let ir = LoadIRFile::new(TEST_CALL);
+    let ir = ir.load().unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let mut dpu = DPU::default();
+    dpu.get_state_mut().insert_commands(ir.iter());
+
+    let mut worker = LocalWorker::new(BuiltinWorker::default(), tx);
+
+    let (_, exit) = dpu.thread_start("ep".into(), None);
+
+    for _ in 0..100 {
+        dpu.process(&rx).unwrap();
+        worker.run();
+    }
+
+    let exit = exit.try_recv().unwrap();
+
+    assert_eq!(exit.result, Ok(()));
+    assert_eq!(exit.ctx.unwrap().get(&"n".into()), Some(ContextValue::Int(42)));
+
+    // the frame is gone with the return
+    assert_eq!(dpu.get_state_mut().contexts.len(), 1);
+}
+
+#[test]
+fn test_builtin_pause() {
+    let ir = LoadIRFile::new(TEST_APPROVAL);
+    let ir = ir.load().unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let mut dpu = DPU::default();
+    dpu.get_state_mut().insert_commands(ir.iter());
+
+    let mut worker = LocalWorker::new(BuiltinWorker::default(), tx.clone());
+
+    let (thread_id, exit) = dpu.thread_start("ep".into(), None);
+
+    for _ in 0..10 {
+        dpu.process(&rx).unwrap();
+        worker.run();
+    }
+
+    let ctx = dpu.get_state_mut().threads.get(&thread_id).unwrap().ctx.clone().unwrap();
+    let pause_id = dpu.context(&ctx).unwrap().get(&"approval".into()).unwrap();
+    let pause_id = pause_id.as_str().unwrap().to_string();
+
+    assert_eq!(dpu.thread_status(&thread_id), Some(ThreadStatus::Paused(pause_id.clone())));
+
+    let mut vals = HashMap::new();
+    vals.insert("approved".to_string(), ContextValue::Bool(true));
+
+    tx.send(DaemonRequest::Unpause(pause_id.clone(), vals)).unwrap();
+
+    for _ in 0..10 {
+        dpu.process(&rx).unwrap();
+        worker.run();
+    }
+
+    let exit = exit.try_recv().unwrap();
+
+    assert_eq!(exit.result, Ok(()));
+    assert_eq!(exit.ctx.unwrap().get(&"approved".into()), Some(ContextValue::Bool(true)));
+
+    // the pause is used up
+    assert!(!dpu.thread_unpause(&pause_id, HashMap::new()));
+}
+
+#[test]
+fn test_builtin_sleep() {
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let started = Instant::now();
+
+    // the thread is woken up by the timer of the daemon loop
+    let daemon = spawn(move || {
+        let ir = LoadIRFile::new(TEST_SLEEP);
+        let ir = ir.load().unwrap();
+
+        let mut dpu = DPU::default();
+        dpu.get_state_mut().insert_commands(ir.iter());
+
+        let (thread_id, _) = dpu.thread_start("ep".into(), None);
+
+        dpu.run(&rx).unwrap();
+
+        dpu.thread_status(&thread_id)
+    });
+
+    let mut worker = LocalWorker::new(BuiltinWorker::default(), tx.clone());
+
+    // ep, 01 and 02
+    let mut executed = 0;
+
+    while executed < 3 && started.elapsed() < Duration::from_secs(5) {
+        executed += worker.run();
+        sleep(Duration::from_millis(1));
+    }
+
+    tx.send(DaemonRequest::Shutdown).unwrap();
+
+    assert_eq!(daemon.join().unwrap(), Some(ThreadStatus::Exited(Ok(()))));
+    assert!(started.elapsed() >= Duration::from_millis(50));
+}
@@ -1,6 +1,17 @@
 
+use std::collections::HashMap;
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+use mio_extras::channel::{Receiver, Sender, channel};
+
+use crate::builtin::BuiltinWorker;
 use crate::daemon::*;
 use crate::obj::*;
+use crate::pubsub::{QueueLimit, QueueWeight, Selection};
+use crate::tests::builtin::TEST_COUNT;
+use crate::tests::prog::LoadIRFile;
+use crate::worker::{LocalWorker, Worker};
 
 fn create_machine() {
     let mut dpu = DPU::default();
@@ -9,20 +20,20 @@ fn create_machine() {
         vec![
             Cmd::create(
                 "0".to_string(),
-                CmdArg::Const("nop".to_string()),
+                CmdArg::Const("nop".into()),
                 vec![],
             ),
             Cmd::create(
                 "1".to_string(),
-                CmdArg::Const("ld".to_string()),
+                CmdArg::Const("ld".into()),
                 vec![
-                    CmdArg::Const("addr".to_string()),
-                    CmdArg::Const("0".to_string()),
+                    CmdArg::Const("addr".into()),
+                    CmdArg::Const("0".into()),
                 ],
             ),
             Cmd::create(
                 "2".to_string(),
-                CmdArg::Const("jmp".to_string()),
+                CmdArg::Const("jmp".into()),
                 vec![
                     CmdArg::Ref(CtxRef(CtxNs::Curr, "addr".to_string()))
                 ],
@@ -54,15 +65,15 @@ fn create_machine_err() {
             ),
             Cmd::create(
                 "1".to_string(),
-                CmdArg::Const("ld".to_string()),
+                CmdArg::Const("ld".into()),
                 vec![
                     CmdArg::Ref(CtxRef(CtxNs::Curr, "addr".to_string())),
-                    CmdArg::Const("0".to_string()),
+                    CmdArg::Const("0".into()),
                 ],
             ),
             Cmd::create(
                 "2".to_string(),
-                CmdArg::Const("jmp".to_string()),
+                CmdArg::Const("jmp".into()),
                 vec![
                     CmdArg::Ref(CtxRef(CtxNs::Curr, "addr".to_string()))
                 ],
@@ -90,3 +101,1143 @@ fn test_machine() {
 fn test_machine_err() {
         create_machine_err();
     }
+
+#[test]
+fn test_run_until_shutdown() {
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let daemon = spawn(move || {
+        let ir = LoadIRFile::new(TEST_COUNT);
+        let ir = ir.load().unwrap();
+
+        let mut dpu = DPU::default();
+        dpu.get_state_mut().insert_commands(ir.iter());
+
+        let (thread_id, _) = dpu.thread_start("ep".into(), None);
+
+        dpu.run(&rx).unwrap();
+
+        dpu.thread_status(&thread_id)
+    });
+
+    let mut worker = LocalWorker::new(BuiltinWorker::default(), tx.clone());
+
+    // ep, 01, three iterations of 02-04 and the final 02, 03, 05
+    let mut executed = 0;
+    let started = Instant::now();
+
+    while executed < 14 && started.elapsed() < Duration::from_secs(5) {
+        executed += worker.run();
+        sleep(Duration::from_millis(1));
+    }
+
+    tx.send(DaemonRequest::Shutdown).unwrap();
+
+    assert_eq!(
+        daemon.join().unwrap(),
+        Some(ThreadStatus::Exited(Ok(()))),
+    );
+}
+
+#[test]
+fn test_run_senders_dropped() {
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let daemon = spawn(move || DPU::default().run(&rx).is_ok());
+
+    drop(tx);
+
+    assert!(daemon.join().unwrap());
+}
+
+#[test]
+fn test_thread_exit_notified() {
+    let ir = LoadIRFile::new(TEST_COUNT);
+    let ir = ir.load().unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let mut dpu = DPU::default();
+    dpu.get_state_mut().insert_commands(ir.iter());
+
+    let mut worker = LocalWorker::new(BuiltinWorker::default(), tx);
+
+    let (thread_id, exit) = dpu.thread_start("ep".into(), None);
+
+    let mut notified = None;
+
+    for _ in 0..100 {
+        dpu.process(&rx).unwrap();
+        worker.run();
+
+        if let Ok(x) = exit.try_recv() {
+            notified = Some(x);
+            break;
+        }
+    }
+
+    let notified = notified.unwrap();
+
+    assert_eq!(notified.id, thread_id);
+    assert_eq!(notified.result, Ok(()));
+    assert_eq!(notified.ctx.unwrap().get(&"i".into()), Some(ContextValue::Int(3)));
+
+    // subscribing after the fact is answered right away
+    let late = dpu.thread_subscribe(&thread_id).unwrap();
+
+    assert_eq!(late.try_recv().unwrap().id, thread_id);
+    assert!(dpu.thread_subscribe(&"nx".into()).is_none());
+}
+
+#[test]
+fn test_snapshot_restore() {
+    let path = std::env::temp_dir().join(format!("yci-snapshot-{}.json", std::process::id()));
+
+    let ir = LoadIRFile::new(TEST_COUNT);
+    let ir = ir.load().unwrap();
+
+    let mut dpu = DPU::default();
+    dpu.get_state_mut().insert_commands(ir.iter());
+
+    // no workers are attached, so the thread stays queued at `push`
+    let (thread_id, _) = dpu.thread_start("ep".into(), None);
+
+    dpu.snapshot(&path).unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let mut dpu = DPU::restore(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(dpu.thread_status(&thread_id), Some(ThreadStatus::Running("ep".into())));
+
+    let exit = dpu.thread_subscribe(&thread_id).unwrap();
+    let mut worker = LocalWorker::new(BuiltinWorker::default(), tx);
+
+    for _ in 0..100 {
+        dpu.process(&rx).unwrap();
+        worker.run();
+    }
+
+    let exit = exit.try_recv().unwrap();
+
+    assert_eq!(exit.result, Ok(()));
+    assert_eq!(exit.ctx.unwrap().get(&"i".into()), Some(ContextValue::Int(3)));
+}
+
+/// A worker of the builtin opcodes whose results are sent by the test.
+struct ManualWorker {
+    id: WorkerId,
+    rx: Receiver<DaemonWorker>,
+    tx: Sender<DaemonRequest>,
+}
+
+impl ManualWorker {
+    fn new(dpu: &mut DPU, tx: &Sender<DaemonRequest>, rx: &Receiver<DaemonRequest>) -> Self {
+        let (wtx, wrx) = channel::<DaemonWorker>();
+        tx.send(DaemonRequest::WorkerAdd(WorkerInfo(None, BuiltinWorker::default().queues()), wtx)).unwrap();
+
+        dpu.process(rx).unwrap();
+
+        match wrx.try_recv() {
+            Ok(DaemonWorker::WorkerCreated(id)) => ManualWorker { id, rx: wrx, tx: tx.clone() },
+            x => panic!("{:?}", x),
+        }
+    }
+
+    /// Send the result of the job the worker was handed for the thread, returning its step.
+    fn finish(&self, thread_id: &ThreadId, res: Result<Vec<Op>, WorkerErr>) -> StepId {
+        loop {
+            match self.rx.try_recv() {
+                Ok(DaemonWorker::JobAssigned(x, step, queue, _)) if &x == thread_id => {
+                    self.tx.send(DaemonRequest::Finished(self.id.clone(), x, step, queue, res)).unwrap();
+
+                    return step;
+                }
+                Ok(_) => continue,
+                x => panic!("{:?}", x),
+            }
+        }
+    }
+
+    fn remove(self, dpu: &mut DPU, rx: &Receiver<DaemonRequest>) {
+        self.tx.send(DaemonRequest::WorkerRemove(self.id)).unwrap();
+
+        dpu.process(rx).unwrap();
+    }
+}
+
+/// Return the ops as the result of the first step of a thread.
+fn exec_ops(dpu: &mut DPU, ops: Vec<Op>) -> Option<ThreadExit> {
+    let ir = LoadIRFile::new(TEST_COUNT);
+    let ir = ir.load().unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    dpu.get_state_mut().insert_commands(ir.iter());
+
+    let worker = ManualWorker::new(dpu, &tx, &rx);
+
+    let (thread_id, exit) = dpu.thread_start("ep".into(), None);
+
+    dpu.process(&rx).unwrap();
+
+    worker.finish(&thread_id, Ok(ops));
+    dpu.process(&rx).unwrap();
+
+    // the next step of the thread stays queued
+    worker.remove(dpu, &rx);
+
+    exit.try_recv().ok()
+}
+
+fn ctx_create(dpu: &mut DPU, id: &str, vals: &[(&str, &str)]) {
+    let vals = vals.iter().map(|(k, v)| (k.to_string(), ContextValue::from(*v))).collect();
+
+    dpu.get_state_mut().insert_context(&Ctx::create(id.into(), vals));
+}
+
+fn op_err(exit: Option<ThreadExit>) -> OpErr {
+    match exit.unwrap().result {
+        Err(ThreadError::WorkerPost(err)) => err,
+        x => panic!("{:?}", x),
+    }
+}
+
+#[test]
+fn test_exec_all_or_nothing() {
+    let mut dpu = DPU::default();
+    ctx_create(&mut dpu, "shared", &[]);
+
+    let err = op_err(exec_ops(&mut dpu, vec![
+        Op::ContextSet(
+            RValueLocal::Const("shared".into()),
+            RValueLocal::Const("a".into()),
+            RValueLocal::Const("1".into()),
+        ),
+        Op::ContextRemove(RValueLocal::Const("nx".into())),
+    ]));
+
+    assert_eq!(err.op_index, Some(1));
+    assert_eq!(err.op_reason, OpErrReason::ContextDoesNotExist { id: "nx".into() });
+
+    assert_eq!(dpu.context(&"shared".into()).unwrap().get(&"a".into()), None);
+}
+
+#[test]
+fn test_context_bulk_ops() {
+    let mut dpu = DPU::default();
+    ctx_create(&mut dpu, "a", &[("x", "1"), ("y", "2")]);
+    ctx_create(&mut dpu, "b", &[("z", "3")]);
+
+    let exit = exec_ops(&mut dpu, vec![
+        Op::LocalSet("c".into(), RValue::Extern(RValueExtern::ContextCopy(RValueLocal::Const("a".into())))),
+        Op::ContextSetMany(RValueLocal::Ref("c".into()), vec![
+            (RValueLocal::Const("w".into()), RValueLocal::Const("4".into())),
+            (RValueLocal::Const("x".into()), RValueLocal::Const("5".into())),
+        ]),
+        Op::ContextUnset(RValueLocal::Const("a".into()), RValueLocal::Const("y".into())),
+        Op::ContextMerge(RValueLocal::Const("b".into()), RValueLocal::Ref("c".into())),
+        Op::LocalSet(LOCAL_CTX.into(), RValue::Local(RValueLocal::Ref("c".into()))),
+        Op::ThreadExit,
+    ]).unwrap();
+
+    assert_eq!(exit.result, Ok(()));
+
+    let c = exit.ctx.unwrap();
+
+    for (k, v) in &[("w", "4"), ("x", "5"), ("y", "2"), ("z", "3")] {
+        assert_eq!(c.get(&k.to_string()), Some(ContextValue::from(*v)));
+    }
+
+    let a = dpu.context(&"a".into()).unwrap();
+
+    assert_eq!(a.get(&"x".into()), Some("1".into()));
+    assert_eq!(a.get(&"y".into()), None);
+}
+
+#[test]
+fn test_context_bulk_ops_err() {
+    let mut dpu = DPU::default();
+    ctx_create(&mut dpu, "a", &[]);
+
+    let cases = vec![
+        (
+            Op::LocalSet("c".into(), RValue::Extern(RValueExtern::ContextCopy(RValueLocal::Const("nx".into())))),
+            OpErrReason::ContextCopyInvalid { src: "nx".into() },
+        ),
+        (
+            Op::ContextSetMany(RValueLocal::Const("nx".into()), vec![]),
+            OpErrReason::ContextSetManyInvalid { id: "nx".into() },
+        ),
+        (
+            Op::ContextUnset(RValueLocal::Const("a".into()), RValueLocal::Const("y".into())),
+            OpErrReason::ContextKeyDoesNotExist { id: "a".into(), ident: "y".into() },
+        ),
+        (
+            Op::ContextUnset(RValueLocal::Const("nx".into()), RValueLocal::Const("y".into())),
+            OpErrReason::ContextDoesNotExist { id: "nx".into() },
+        ),
+        (
+            Op::ContextMerge(RValueLocal::Const("a".into()), RValueLocal::Const("nx".into())),
+            OpErrReason::ContextMergeInvalid { id: "nx".into() },
+        ),
+    ];
+
+    for (op, reason) in cases {
+        let err = op_err(exec_ops(&mut dpu, vec![op]));
+
+        assert_eq!(err.op_index, Some(0));
+        assert_eq!(err.op_reason, reason);
+    }
+}
+
+#[test]
+fn test_context_set_null() {
+    let mut dpu = DPU::default();
+    ctx_create(&mut dpu, "shared", &[("a", "1")]);
+
+    let exit = exec_ops(&mut dpu, vec![
+        Op::ContextSet(
+            RValueLocal::Const("shared".into()),
+            RValueLocal::Const("a".into()),
+            RValueLocal::Const(ContextValue::Null),
+        ),
+        Op::LocalSet(LOCAL_CTX.into(), RValue::Local(RValueLocal::Const(ContextValue::Null))),
+        Op::ThreadExit,
+    ]).unwrap();
+
+    assert_eq!(exit.result, Ok(()));
+    assert_eq!(exit.ctx, None);
+
+    assert_eq!(dpu.context(&"shared".into()).unwrap().get(&"a".into()), Some(ContextValue::Null));
+}
+
+#[test]
+fn test_list_ops() {
+    let mut dpu = DPU::default();
+    dpu.get_state_mut().insert_context(&Ctx::create("a".into(), vec![
+        ("xs".to_string(), ContextValue::List(vec![])),
+    ].into_iter().collect()));
+
+    let exit = exec_ops(&mut dpu, vec![
+        Op::ListAppend(
+            RValueLocal::Const("a".into()),
+            RValueLocal::Const("xs".into()),
+            RValueLocal::Const(ContextValue::Int(1)),
+        ),
+        Op::ListAppend(
+            RValueLocal::Const("a".into()),
+            RValueLocal::Const("xs".into()),
+            RValueLocal::Const(ContextValue::Bool(true)),
+        ),
+        Op::LocalSet("x".into(), RValue::Extern(RValueExtern::ListGet(
+            RValueLocal::Const("a".into()),
+            RValueLocal::Const("xs".into()),
+            RValueLocal::Const(ContextValue::Int(1)),
+        ))),
+        Op::ContextSet(
+            RValueLocal::Const("a".into()),
+            RValueLocal::Const("x".into()),
+            RValueLocal::Ref("x".into()),
+        ),
+        Op::ThreadExit,
+    ]).unwrap();
+
+    assert_eq!(exit.result, Ok(()));
+
+    let a = dpu.context(&"a".into()).unwrap();
+
+    assert_eq!(a.get(&"xs".into()), Some(ContextValue::List(vec![ContextValue::Int(1), ContextValue::Bool(true)])));
+    assert_eq!(a.get(&"x".into()), Some(ContextValue::Bool(true)));
+}
+
+#[test]
+fn test_list_ops_err() {
+    let mut dpu = DPU::default();
+    ctx_create(&mut dpu, "a", &[("s", "1")]);
+
+    let cases = vec![
+        (
+            Op::ListAppend(
+                RValueLocal::Const("a".into()),
+                RValueLocal::Const("s".into()),
+                RValueLocal::Const("2".into()),
+            ),
+            OpErrReason::ListInvalid { id: "a".into(), ident: "s".into() },
+        ),
+        (
+            Op::LocalSet("x".into(), RValue::Extern(RValueExtern::ListGet(
+                RValueLocal::Const("a".into()),
+                RValueLocal::Const("nx".into()),
+                RValueLocal::Const(ContextValue::Int(0)),
+            ))),
+            OpErrReason::ListInvalid { id: "a".into(), ident: "nx".into() },
+        ),
+        (
+            Op::ContextSet(
+                RValueLocal::Const(ContextValue::Int(1)),
+                RValueLocal::Const("s".into()),
+                RValueLocal::Const("2".into()),
+            ),
+            OpErrReason::IdInvalid(ContextValue::Int(1)),
+        ),
+    ];
+
+    for (op, reason) in cases {
+        let err = op_err(exec_ops(&mut dpu, vec![op]));
+
+        assert_eq!(err.op_index, Some(0));
+        assert_eq!(err.op_reason, reason);
+    }
+
+    dpu.get_state_mut().insert_context(&Ctx::create("b".into(), vec![
+        ("xs".to_string(), ContextValue::List(vec!["a".into()])),
+    ].into_iter().collect()));
+
+    let err = op_err(exec_ops(&mut dpu, vec![
+        Op::LocalSet("x".into(), RValue::Extern(RValueExtern::ListGet(
+            RValueLocal::Const("b".into()),
+            RValueLocal::Const("xs".into()),
+            RValueLocal::Const(ContextValue::Int(1)),
+        ))),
+    ]));
+
+    assert_eq!(err.op_reason, OpErrReason::ListIndexInvalid { index: ContextValue::Int(1) });
+}
+
+#[test]
+fn test_call_return() {
+    let mut dpu = DPU::default();
+    ctx_create(&mut dpu, "a", &[]);
+
+    let exit = exec_ops(&mut dpu, vec![
+        Op::LocalSet(LOCAL_CTX.into(), RValue::Local(RValueLocal::Const("a".into()))),
+        Op::Call(RValueLocal::Const("10".into()), RValueLocal::Const("05".into())),
+        Op::ContextSet(
+            RValueLocal::Ref(LOCAL_CTX.into()),
+            RValueLocal::Const("x".into()),
+            RValueLocal::Ref(LOCAL_NIP.into()),
+        ),
+        Op::Return,
+        Op::ThreadExit,
+    ]).unwrap();
+
+    assert_eq!(exit.result, Ok(()));
+    assert_eq!(exit.ctx.map(|x| x.id().clone()), Some("a".into()));
+    assert_eq!(dpu.get_state_mut().contexts.len(), 1);
+
+    let err = op_err(exec_ops(&mut dpu, vec![
+        Op::LocalSet(LOCAL_CTX.into(), RValue::Local(RValueLocal::Const("a".into()))),
+        Op::Return,
+    ]));
+
+    assert_eq!(err.op_index, Some(1));
+    assert_eq!(err.op_reason, OpErrReason::FrameInvalid { id: "a".into() });
+}
+
+#[test]
+fn test_exception_value() {
+    let ir = LoadIRFile::new(TEST_COUNT);
+    let ir = ir.load().unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let mut dpu = DPU::default();
+    dpu.get_state_mut().insert_commands(ir.iter());
+
+    let worker = ManualWorker::new(&mut dpu, &tx, &rx);
+
+    let (thread_id, _) = dpu.thread_start("ep".into(), None);
+
+    dpu.process(&rx).unwrap();
+
+    worker.finish(&thread_id, Ok(vec![
+        Op::LocalSet(LOCAL_CTX.into(), RValue::Extern(RValueExtern::ContextCreate)),
+        Op::LocalSet(LOCAL_EIP.into(), RValue::Local(RValueLocal::Const("05".into()))),
+        Op::LocalSet(LOCAL_NIP.into(), RValue::Local(RValueLocal::Const("01".into()))),
+    ]));
+    dpu.process(&rx).unwrap();
+
+    let mut fields = HashMap::new();
+    fields.insert("code".to_string(), "E1".to_string());
+
+    worker.finish(&thread_id, Err(WorkerErr::Custom(fields)));
+    dpu.process(&rx).unwrap();
+
+    let thread = dpu.get_state_mut().threads.get(&thread_id).unwrap().clone();
+
+    assert_eq!(thread.ip, "05");
+
+    let frame = dpu.context(thread.ctx.as_ref().unwrap()).unwrap().clone();
+
+    let path = |x: &[&str]| CtxNs::Ref(x.iter().map(|x| x.to_string()).collect());
+
+    let cmd = Cmd::create("0".into(), CmdArg::Const("nop".into()), vec![
+        CmdArg::Ref(CtxRef(path(&[LOCAL_EXC]), "kind".into())),
+        CmdArg::Ref(CtxRef(path(&[LOCAL_EXC, "custom"]), "code".into())),
+        CmdArg::Ref(CtxRef(path(&[LOCAL_EXC]), "reason".into())),
+    ]);
+
+    let state = dpu.get_state_mut();
+
+    assert_eq!(
+        DPU::interpolate(state, &cmd, Some(&frame)),
+        Err(InterpolationError::Ref(CtxRef(path(&[LOCAL_EXC]), "reason".into()))),
+    );
+
+    let cmd = Cmd::create("0".into(), CmdArg::Const("nop".into()), cmd.args[..2].to_vec());
+
+    assert_eq!(
+        DPU::interpolate(state, &cmd, Some(&frame)),
+        Ok(XCmd::create("0".into(), "nop".into(), vec![
+            XCmdArg::Const("WorkerDuring".into()),
+            XCmdArg::Const("E1".into()),
+        ])),
+    );
+}
+
+#[test]
+fn test_raise_queued() {
+    let ir = LoadIRFile::new(TEST_COUNT);
+    let ir = ir.load().unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let mut dpu = DPU::default();
+    dpu.get_state_mut().insert_commands(ir.iter());
+
+    // no workers are attached yet, so the thread stays queued at `push`
+    let (thread_id, exit) = dpu.thread_start("ep".into(), None);
+
+    tx.send(DaemonRequest::Raise(thread_id.clone(), "stop".into())).unwrap();
+    dpu.process(&rx).unwrap();
+
+    assert_eq!(exit.try_recv().unwrap().result, Err(ThreadError::Raised("stop".into())));
+
+    // the job is gone from the queue
+    let mut worker = LocalWorker::new(BuiltinWorker::default(), tx);
+
+    dpu.process(&rx).unwrap();
+
+    assert_eq!(worker.run(), 0);
+
+    assert!(!dpu.thread_raise(&thread_id, "again".into()));
+}
+
+#[test]
+fn test_raise_assigned() {
+    let ir = LoadIRFile::new(TEST_COUNT);
+    let ir = ir.load().unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let mut dpu = DPU::default();
+    dpu.get_state_mut().insert_commands(ir.iter());
+
+    let mut worker = LocalWorker::new(BuiltinWorker::default(), tx);
+
+    let (thread_id, exit) = dpu.thread_start("ep".into(), None);
+
+    // the worker is handed `push`, but does not get to run it before the exception is raised
+    dpu.process(&rx).unwrap();
+
+    let thread = dpu.get_state_mut().threads.get_mut(&thread_id).unwrap();
+
+    assert!(matches!(thread.state, ThreadState::Assigned(_, _)));
+
+    thread.eip = Some("05".into());
+
+    assert!(dpu.thread_raise(&thread_id, "stop".into()));
+
+    for _ in 0..10 {
+        worker.run();
+        dpu.process(&rx).unwrap();
+    }
+
+    let exit = exit.try_recv().unwrap();
+
+    assert_eq!(exit.result, Ok(()));
+
+    // the late result of `push` did not replace the context of the handler
+    let exc = exit.ctx.unwrap().get(&LOCAL_EXC.into()).unwrap();
+
+    assert_eq!(exc.as_map().unwrap().get("kind"), Some(&ContextValue::from("Raised")));
+    assert_eq!(exc.as_map().unwrap().get("payload"), Some(&ContextValue::from("stop")));
+}
+
+#[test]
+fn test_thread_remove_queued() {
+    let ir = LoadIRFile::new(TEST_COUNT);
+    let ir = ir.load().unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let mut dpu = DPU::default();
+    dpu.get_state_mut().insert_commands(ir.iter());
+
+    // the worker only takes one job at a time, so the other thread stays queued at `push`
+    let (wtx, wrx) = channel::<DaemonWorker>();
+    tx.send(DaemonRequest::WorkerAdd(WorkerInfo(Some(1), vec!["push".into()]), wtx)).unwrap();
+
+    let (thread_id, exit) = dpu.thread_start("ep".into(), None);
+    dpu.process(&rx).unwrap();
+
+    let (removed, _) = dpu.thread_start("ep".into(), None);
+    dpu.process(&rx).unwrap();
+
+    let worker_id = match wrx.try_recv() {
+        Ok(DaemonWorker::WorkerCreated(x)) => x,
+        x => panic!("{:?}", x),
+    };
+
+    let step = match wrx.try_recv() {
+        Ok(DaemonWorker::JobAssigned(x, step, _, _)) if x == thread_id => step,
+        x => panic!("{:?}", x),
+    };
+
+    tx.send(DaemonRequest::Finished(worker_id, thread_id, step, "push".into(), Ok(vec![
+        Op::ThreadRemove(RValueLocal::Const(removed.clone().into())),
+        Op::ThreadExit,
+    ]))).unwrap();
+    dpu.process(&rx).unwrap();
+
+    assert_eq!(exit.try_recv().unwrap().result, Ok(()));
+    assert_eq!(dpu.thread_status(&removed), None);
+
+    // the job of the removed thread is gone from the queue, not handed to the worker it frees
+    assert!(wrx.try_recv().is_err());
+}
+
+#[test]
+fn test_unpause_early() {
+    let ir = LoadIRFile::new(TEST_COUNT);
+    let ir = ir.load().unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let mut dpu = DPU::default();
+    dpu.get_state_mut().insert_commands(ir.iter());
+
+    let worker = ManualWorker::new(&mut dpu, &tx, &rx);
+
+    let (thread_id, _) = dpu.thread_start("ep".into(), None);
+
+    dpu.process(&rx).unwrap();
+
+    // the identifier is handed out a step before the thread pauses
+    worker.finish(&thread_id, Ok(vec![
+        Op::LocalSet(LOCAL_CTX.into(), RValue::Extern(RValueExtern::ContextCreate)),
+        Op::LocalSet("p".into(), RValue::Extern(RValueExtern::PauseCreate)),
+        Op::ContextSet(
+            RValueLocal::Ref(LOCAL_CTX.into()),
+            RValueLocal::Const("p".into()),
+            RValueLocal::Ref("p".into()),
+        ),
+        Op::LocalSet(LOCAL_NIP.into(), RValue::Local(RValueLocal::Const("01".into()))),
+    ]));
+    dpu.process(&rx).unwrap();
+
+    let ctx = dpu.get_state_mut().threads.get(&thread_id).unwrap().ctx.clone().unwrap();
+    let pause_id = dpu.context(&ctx).unwrap().get(&"p".into()).unwrap().as_str().unwrap().to_string();
+
+    let mut vals = HashMap::new();
+    vals.insert("x".to_string(), ContextValue::Int(1));
+
+    assert!(dpu.thread_unpause(&pause_id, vals.clone()));
+
+    // only the first unpause is kept until the thread pauses
+    assert!(!dpu.thread_unpause(&pause_id, vals));
+
+    worker.finish(&thread_id, Ok(vec![
+        Op::ThreadPause(RValueLocal::Const(pause_id.clone().into())),
+        Op::LocalSet(LOCAL_NIP.into(), RValue::Local(RValueLocal::Const("05".into()))),
+    ]));
+    dpu.process(&rx).unwrap();
+
+    assert_eq!(dpu.thread_status(&thread_id), Some(ThreadStatus::Running("05".into())));
+    assert_eq!(dpu.context(&ctx).unwrap().get(&"x".into()), Some(ContextValue::Int(1)));
+
+    let err = op_err(exec_ops(&mut dpu, vec![
+        Op::ThreadPause(RValueLocal::Const(pause_id.clone().into())),
+    ]));
+
+    assert_eq!(err.op_reason, OpErrReason::PauseInvalid { id: pause_id });
+}
+
+#[test]
+fn test_pause_removed_on_exit() {
+    let mut dpu = DPU::default();
+
+    // the thread exits in the same batch, without ever pausing
+    let exit = exec_ops(&mut dpu, vec![
+        Op::LocalSet(LOCAL_CTX.into(), RValue::Extern(RValueExtern::ContextCreate)),
+        Op::LocalSet("p".into(), RValue::Extern(RValueExtern::PauseCreate)),
+        Op::ContextSet(
+            RValueLocal::Ref(LOCAL_CTX.into()),
+            RValueLocal::Const("p".into()),
+            RValueLocal::Ref("p".into()),
+        ),
+        Op::ThreadExit,
+    ]).unwrap();
+
+    assert_eq!(exit.result, Ok(()));
+
+    let pause_id = exit.ctx.unwrap().get(&"p".into()).unwrap().as_str().unwrap().to_string();
+
+    assert!(dpu.get_state_mut().pauses.is_empty());
+    assert!(!dpu.thread_unpause(&pause_id, HashMap::new()));
+    assert!(dpu.get_state_mut().pauses.is_empty());
+}
+
+#[test]
+fn test_resume_at() {
+    let mut dpu = DPU::default();
+
+    assert!(exec_ops(&mut dpu, vec![
+        Op::ResumeAt(RValueLocal::Const(ContextValue::Int(1000))),
+        Op::LocalSet(LOCAL_NIP.into(), RValue::Local(RValueLocal::Const("05".into()))),
+    ]).is_none());
+
+    let thread_id = dpu.get_state_mut().threads.keys().next().unwrap().clone();
+
+    assert_eq!(dpu.thread_status(&thread_id), Some(ThreadStatus::Sleeping(1000)));
+    assert_eq!(dpu.next_wake(), Some(1000));
+
+    assert_eq!(dpu.wake(999), 0);
+    assert_eq!(dpu.wake(1000), 1);
+
+    // queued at `exit`, as no workers are attached
+    assert_eq!(dpu.thread_status(&thread_id), Some(ThreadStatus::Running("05".into())));
+    assert_eq!(dpu.next_wake(), None);
+}
+
+#[test]
+fn test_sleep_err() {
+    let mut dpu = DPU::default();
+
+    let err = op_err(exec_ops(&mut dpu, vec![
+        Op::Sleep(RValueLocal::Const(ContextValue::Int(-1))),
+    ]));
+
+    assert_eq!(err.op_reason, OpErrReason::DeadlineInvalid(ContextValue::Int(-1)));
+
+    let err = op_err(exec_ops(&mut dpu, vec![
+        Op::ResumeAt(RValueLocal::Const("soon".into())),
+    ]));
+
+    assert_eq!(err.op_reason, OpErrReason::DeadlineInvalid("soon".into()));
+}
+
+#[test]
+fn test_raise_sleeping() {
+    let mut dpu = DPU::default();
+
+    exec_ops(&mut dpu, vec![
+        Op::ResumeAt(RValueLocal::Const(ContextValue::Int(1000))),
+    ]);
+
+    let thread_id = dpu.get_state_mut().threads.keys().next().unwrap().clone();
+
+    assert!(dpu.thread_raise(&thread_id, "stop".into()));
+
+    // the deadline left behind does not wake the thread up again
+    assert_eq!(dpu.wake(1000), 0);
+    assert_eq!(
+        dpu.thread_status(&thread_id),
+        Some(ThreadStatus::Exited(Err(ThreadError::Raised("stop".into())))),
+    );
+}
+
+#[test]
+fn test_timeout_requeue() {
+    let ir = LoadIRFile::new(TEST_COUNT);
+    let ir = ir.load().unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let mut dpu = DPU::default();
+    dpu.get_state_mut().insert_commands(ir.iter());
+    dpu.queue_timeout("push".into(), Some(QueueTimeout { after: 1000, requeue: 1 }));
+
+    let mut worker = LocalWorker::new(BuiltinWorker::default(), tx);
+
+    let (thread_id, exit) = dpu.thread_start("ep".into(), None);
+
+    // the job is assigned, but the worker does not get to run it
+    dpu.process(&rx).unwrap();
+
+    let step = dpu.get_state_mut().threads.get(&thread_id).unwrap().step;
+    let deadline = dpu.next_expiry().unwrap();
+
+    assert_eq!(dpu.expire(deadline - 1), 0);
+    assert_eq!(dpu.expire(deadline), 1);
+
+    assert_eq!(dpu.get_state_mut().threads.get(&thread_id).unwrap().step, step + 1);
+    assert_eq!(dpu.thread_status(&thread_id), Some(ThreadStatus::Running("ep".into())));
+
+    // the late result of the first assignment is dropped
+    for _ in 0..100 {
+        dpu.process(&rx).unwrap();
+        worker.run();
+    }
+
+    let exit = exit.try_recv().unwrap();
+
+    assert_eq!(exit.result, Ok(()));
+    assert_eq!(exit.ctx.unwrap().get(&"i".into()), Some(ContextValue::Int(3)));
+}
+
+#[test]
+fn test_timeout_fail() {
+    let ir = LoadIRFile::new(TEST_COUNT);
+    let ir = ir.load().unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let mut dpu = DPU::default();
+    dpu.get_state_mut().insert_commands(ir.iter());
+    dpu.queue_timeout("push".into(), Some(QueueTimeout { after: 1000, requeue: 0 }));
+
+    let mut worker = LocalWorker::new(BuiltinWorker::default(), tx);
+
+    let (thread_id, exit) = dpu.thread_start("ep".into(), None);
+
+    dpu.process(&rx).unwrap();
+
+    let deadline = dpu.next_expiry().unwrap();
+
+    assert_eq!(dpu.expire(deadline), 1);
+
+    match exit.try_recv().unwrap().result {
+        Err(ThreadError::Timeout { queue, .. }) => assert_eq!(queue, "push"),
+        x => panic!("{:?}", x),
+    }
+
+    worker.run();
+    dpu.process(&rx).unwrap();
+
+    assert!(matches!(dpu.thread_status(&thread_id), Some(ThreadStatus::Exited(Err(ThreadError::Timeout { .. })))));
+    assert_eq!(dpu.next_expiry(), None);
+}
+
+#[test]
+fn test_queue_config() {
+    let ir = LoadIRFile::new(TEST_COUNT);
+    let ir = ir.load().unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let queues: HashMap<CommandId, QueueConfig> = serde_json::from_str(r#"{
+        "push": {"timeout": {"after": 1000, "requeue": 0}},
+        "set": {}
+    }"#).unwrap();
+
+    assert_eq!(queues.get("set"), Some(&QueueConfig::default()));
+
+    let config: QueueConfig = serde_json::from_str(r#"{
+        "limit": {"concurrency": 2},
+        "weight": {"priority": 1},
+        "selection": "RoundRobin"
+    }"#).unwrap();
+
+    assert_eq!(config, QueueConfig {
+        limit: Some(QueueLimit { concurrency: Some(2), rate: None }),
+        weight: Some(QueueWeight { priority: 1, weight: 1 }),
+        selection: Some(Selection::RoundRobin),
+        ..QueueConfig::default()
+    });
+
+    let mut dpu = DPU::default();
+    dpu.get_state_mut().insert_commands(ir.iter());
+
+    for (queue, config) in queues {
+        dpu.queue_config(queue, config);
+    }
+
+    let _worker = LocalWorker::new(BuiltinWorker::default(), tx);
+
+    let (_, exit) = dpu.thread_start("ep".into(), None);
+
+    dpu.process(&rx).unwrap();
+
+    let deadline = dpu.next_expiry().unwrap();
+
+    assert_eq!(dpu.expire(deadline), 1);
+    assert!(matches!(exit.try_recv().unwrap().result, Err(ThreadError::Timeout { .. })));
+}
+
+fn custom_err(code: &str) -> WorkerErr {
+    let mut fields = HashMap::new();
+    fields.insert("code".to_string(), code.to_string());
+
+    WorkerErr::Custom(fields)
+}
+
+#[test]
+fn test_retry_policy_delay() {
+    let policy = RetryPolicy {
+        attempts: 3,
+        backoff: 100,
+        codes: Some(vec!["503".to_string()].into_iter().collect()),
+    };
+
+    assert_eq!(policy.delay(&custom_err("503"), 0), Some(100));
+    assert_eq!(policy.delay(&custom_err("503"), 1), Some(200));
+    assert_eq!(policy.delay(&custom_err("503"), 2), None);
+
+    assert_eq!(policy.delay(&custom_err("400"), 0), None);
+    assert_eq!(policy.delay(&WorkerErr::Default(OpErrReason::UnknownOp), 0), None);
+
+    let policy = RetryPolicy { codes: None, ..policy };
+
+    assert_eq!(policy.delay(&custom_err("400"), 0), Some(100));
+}
+
+#[test]
+fn test_retry_backoff() {
+    let ir = LoadIRFile::new(TEST_COUNT);
+    let ir = ir.load().unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let mut dpu = DPU::default();
+    dpu.get_state_mut().insert_commands(ir.iter());
+    dpu.queue_retry("push".into(), Some(RetryPolicy { attempts: 2, backoff: 1000, codes: None }));
+
+    let worker = ManualWorker::new(&mut dpu, &tx, &rx);
+
+    let (thread_id, exit) = dpu.thread_start("ep".into(), None);
+
+    dpu.process(&rx).unwrap();
+
+    let step = worker.finish(&thread_id, Err(custom_err("503")));
+    dpu.process(&rx).unwrap();
+
+    assert_eq!(dpu.thread_status(&thread_id), Some(ThreadStatus::Running("ep".into())));
+
+    let deadline = dpu.next_wake().unwrap();
+
+    assert_eq!(dpu.wake(deadline - 1), 0);
+    assert_eq!(dpu.wake(deadline), 1);
+
+    // the same command is queued again with the next step
+    assert_eq!(dpu.get_state_mut().threads.get(&thread_id).unwrap().step, step + 1);
+
+    dpu.process(&rx).unwrap();
+
+    worker.finish(&thread_id, Err(custom_err("503")));
+    dpu.process(&rx).unwrap();
+
+    assert_eq!(exit.try_recv().unwrap().result, Err(ThreadError::WorkerDuring(custom_err("503"))));
+}
+
+#[test]
+fn test_worker_remove_requeue() {
+    let ir = LoadIRFile::new(TEST_COUNT);
+    let ir = ir.load().unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let mut dpu = DPU::default();
+    dpu.get_state_mut().insert_commands(ir.iter());
+    dpu.queue_timeout("push".into(), Some(QueueTimeout { after: 1000, requeue: 0 }));
+
+    let (wtx, wrx) = channel::<DaemonWorker>();
+
+    tx.send(DaemonRequest::WorkerAdd(WorkerInfo(None, vec!["push".into()]), wtx)).unwrap();
+
+    let (thread_id, exit) = dpu.thread_start("ep".into(), None);
+
+    // the job is assigned, but the worker goes away before running it
+    dpu.process(&rx).unwrap();
+
+    let worker_id = match wrx.try_recv() {
+        Ok(DaemonWorker::WorkerCreated(x)) => x,
+        x => panic!("{:?}", x),
+    };
+
+    let step = match wrx.try_recv() {
+        Ok(DaemonWorker::JobAssigned(_, x, _, _)) => x,
+        x => panic!("{:?}", x),
+    };
+
+    tx.send(DaemonRequest::WorkerRemove(worker_id)).unwrap();
+    dpu.process(&rx).unwrap();
+
+    let thread = dpu.get_state_mut().threads.get(&thread_id).unwrap();
+
+    assert_eq!(thread.step, step);
+    assert!(matches!(thread.state, ThreadState::Queued(_)));
+    assert_eq!(dpu.next_expiry(), None);
+
+    let mut worker = LocalWorker::new(BuiltinWorker::default(), tx);
+
+    for _ in 0..100 {
+        dpu.process(&rx).unwrap();
+        worker.run();
+    }
+
+    let exit = exit.try_recv().unwrap();
+
+    assert_eq!(exit.result, Ok(()));
+    assert_eq!(exit.ctx.unwrap().get(&"i".into()), Some(ContextValue::Int(3)));
+}
+
+#[test]
+fn test_worker_remove_result_dropped() {
+    let ir = LoadIRFile::new(TEST_COUNT);
+    let ir = ir.load().unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let mut dpu = DPU::default();
+    dpu.get_state_mut().insert_commands(ir.iter());
+
+    let (atx, arx) = channel::<DaemonWorker>();
+    tx.send(DaemonRequest::WorkerAdd(WorkerInfo(None, vec!["push".into()]), atx)).unwrap();
+
+    let (thread_id, exit) = dpu.thread_start("ep".into(), None);
+
+    dpu.process(&rx).unwrap();
+
+    let (btx, brx) = channel::<DaemonWorker>();
+    tx.send(DaemonRequest::WorkerAdd(WorkerInfo(None, vec!["push".into()]), btx)).unwrap();
+
+    dpu.process(&rx).unwrap();
+
+    let removed = match arx.try_recv() {
+        Ok(DaemonWorker::WorkerCreated(x)) => x,
+        x => panic!("{:?}", x),
+    };
+
+    let step = match arx.try_recv() {
+        Ok(DaemonWorker::JobAssigned(_, x, _, _)) => x,
+        x => panic!("{:?}", x),
+    };
+
+    let worker_id = match brx.try_recv() {
+        Ok(DaemonWorker::WorkerCreated(x)) => x,
+        x => panic!("{:?}", x),
+    };
+
+    // the job is handed to the other worker straight away
+    tx.send(DaemonRequest::WorkerRemove(removed.clone())).unwrap();
+    dpu.process(&rx).unwrap();
+
+    match brx.try_recv() {
+        Ok(DaemonWorker::JobAssigned(_, x, _, _)) => assert_eq!(x, step),
+        x => panic!("{:?}", x),
+    };
+
+    // the removed worker answers late, its result is not applied
+    let res = Ok(vec![Op::ThreadExit]);
+
+    tx.send(DaemonRequest::Finished(removed, thread_id.clone(), step, "push".into(), res.clone())).unwrap();
+    dpu.process(&rx).unwrap();
+
+    assert_eq!(dpu.get_state_mut().threads.get(&thread_id).unwrap().step, step);
+    assert!(exit.try_recv().is_err());
+
+    tx.send(DaemonRequest::Finished(worker_id, thread_id.clone(), step, "push".into(), res)).unwrap();
+    dpu.process(&rx).unwrap();
+
+    assert!(exit.try_recv().is_ok());
+}
+
+#[test]
+fn test_worker_remove_result_queued() {
+    let ir = LoadIRFile::new(TEST_COUNT);
+    let ir = ir.load().unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let mut dpu = DPU::default();
+    dpu.get_state_mut().insert_commands(ir.iter());
+
+    let (wtx, wrx) = channel::<DaemonWorker>();
+    tx.send(DaemonRequest::WorkerAdd(WorkerInfo(None, vec!["push".into()]), wtx)).unwrap();
+
+    let (thread_id, exit) = dpu.thread_start("ep".into(), None);
+
+    dpu.process(&rx).unwrap();
+
+    let removed = match wrx.try_recv() {
+        Ok(DaemonWorker::WorkerCreated(x)) => x,
+        x => panic!("{:?}", x),
+    };
+
+    let step = match wrx.try_recv() {
+        Ok(DaemonWorker::JobAssigned(_, x, _, _)) => x,
+        x => panic!("{:?}", x),
+    };
+
+    // no other worker is attached, so the job goes back to the queue
+    tx.send(DaemonRequest::WorkerRemove(removed.clone())).unwrap();
+    dpu.process(&rx).unwrap();
+
+    // the removed worker answers late, under a queue the job is not on
+    tx.send(DaemonRequest::Finished(removed, thread_id.clone(), step, "set".into(), Ok(vec![Op::ThreadExit]))).unwrap();
+    dpu.process(&rx).unwrap();
+
+    let thread = dpu.get_state_mut().threads.get(&thread_id).unwrap();
+
+    assert_eq!(thread.step, step);
+    assert!(match &thread.state {
+        ThreadState::Queued(x) => x.opcode == "push",
+        _ => false,
+    });
+    assert!(exit.try_recv().is_err());
+
+    // the job is still queued for the next worker
+    let worker = ManualWorker::new(&mut dpu, &tx, &rx);
+
+    assert_eq!(worker.finish(&thread_id, Ok(vec![Op::ThreadExit])), step);
+    dpu.process(&rx).unwrap();
+
+    assert!(exit.try_recv().is_ok());
+}
+
+#[test]
+fn test_finished_frees_worker() {
+    let ir = LoadIRFile::new(TEST_COUNT);
+    let ir = ir.load().unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let mut dpu = DPU::default();
+    dpu.get_state_mut().insert_commands(ir.iter());
+
+    // the worker only takes one job at a time
+    let (wtx, wrx) = channel::<DaemonWorker>();
+    tx.send(DaemonRequest::WorkerAdd(WorkerInfo(Some(1), vec!["push".into()]), wtx)).unwrap();
+
+    let (first, _) = dpu.thread_start("ep".into(), None);
+    let (second, _) = dpu.thread_start("ep".into(), None);
+
+    dpu.process(&rx).unwrap();
+
+    let worker_id = match wrx.try_recv() {
+        Ok(DaemonWorker::WorkerCreated(x)) => x,
+        x => panic!("{:?}", x),
+    };
+
+    let (thread_id, step, queue) = match wrx.try_recv() {
+        Ok(DaemonWorker::JobAssigned(a, b, c, _)) => (a, b, c),
+        x => panic!("{:?}", x),
+    };
+
+    assert_eq!(queue, "push");
+    assert!(wrx.try_recv().is_err());
+
+    // the result comes back with the queue it was assigned from, which frees the worker
+    tx.send(DaemonRequest::Finished(worker_id, thread_id.clone(), step, queue, Ok(vec![Op::ThreadExit]))).unwrap();
+    dpu.process(&rx).unwrap();
+
+    let other = if thread_id == first { second } else { first };
+
+    match wrx.try_recv() {
+        Ok(DaemonWorker::JobAssigned(x, _, _, _)) => assert_eq!(x, other),
+        x => panic!("{:?}", x),
+    };
+}
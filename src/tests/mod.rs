@@ -3,4 +3,6 @@ mod prog;
 mod pubsub;
 mod daemon;
 mod worker;
-mod net;
\ No newline at end of file
+mod builtin;
+mod net;
+mod wal;
@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+use mio::net::TcpStream;
+use mio_extras::channel::{Sender, Receiver, channel};
+use serde_json::Error as SerdeError;
+
+use crate::builtin::BuiltinWorker;
+use crate::daemon::*;
+use crate::obj::ContextValue;
+use crate::net::api::*;
+use crate::net::parser::*;
+use crate::net::tcp::StreamForwarder;
+use crate::tests::builtin::TEST_COUNT;
+use crate::tests::prog::LoadIRFile;
+use crate::worker::LocalWorker;
+
+pub(crate) struct ClientTcp {
+    rx: Receiver<ClientApiRp>,
+    tx: Sender<ClientApiRq>,
+    chan: StreamForwarder<TcpStream, ClientApiRp, ClientApiRq, SerdeError>,
+    idx: usize,
+}
+
+impl ClientTcp {
+    pub fn new(
+        addr: &SocketAddr,
+    ) -> Result<Self, io::Error> {
+        let sock = std::net::TcpStream::connect(addr)?;
+        let sock = TcpStream::from_stream(sock)?;
+
+        sock.set_nodelay(true)?;
+
+        let (rx, tx, fw) = StreamForwarder::<TcpStream, ClientApiRp, ClientApiRq, SerdeError>::new(sock)?;
+
+        Ok(ClientTcp { rx, tx, chan: fw, idx: 0 })
+    }
+
+    pub fn request(&mut self, rq: ClientRequest) -> usize {
+        let idx = self.idx;
+        self.idx += 1;
+
+        self.tx.send(ClientApiRq::Request(idx, rq)).unwrap();
+        self.chan.tx_loop().expect("a");
+
+        idx
+    }
+
+    pub fn response(&mut self) -> ClientApiRp {
+        let started = Instant::now();
+
+        while started.elapsed() < Duration::from_secs(5) {
+            self.chan.rx_loop().expect("b");
+
+            if let Ok(x) = self.rx.try_recv() {
+                return x;
+            }
+
+            sleep(Duration::from_millis(1));
+        }
+
+        panic!("no response");
+    }
+}
+
+#[test]
+fn test_api_parser() {
+    let rq = ClientApiRq::Request(3, ClientRequest::ThreadStatus("a".into()));
+
+    let packet = write_json_packet(&rq).unwrap();
+
+    assert_eq!(
+        read_json_packet::<ClientApiRq>(&packet),
+        Ok((b"".as_ref(), rq)),
+    );
+}
+
+#[test]
+fn test_api_thread_create() {
+    let addr: SocketAddr = "127.0.0.1:45100".parse().unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let daemon = spawn(move || DPU::default().run(&rx).unwrap());
+
+    let _adapter = TCPClientAdapter::new(&addr, tx.clone()).unwrap();
+
+    let mut worker = LocalWorker::new(BuiltinWorker::default(), tx.clone());
+    let mut client = ClientTcp::new(&addr).unwrap();
+
+    let ir = LoadIRFile::new(TEST_COUNT);
+    let ir = ir.load().unwrap();
+
+    let idx = client.request(ClientRequest::CommandsAdd(ir));
+
+    assert_eq!(
+        client.response(),
+        ClientApiRp::Response(idx, ClientResponse::CommandsAdded(6)),
+    );
+
+    let mut vals = HashMap::new();
+    vals.insert("i".to_string(), "0".into());
+
+    let idx = client.request(ClientRequest::ContextCreate(vals));
+
+    let ctx = match client.response() {
+        ClientApiRp::Response(x, ClientResponse::ContextCreated(ctx)) if x == idx => ctx,
+        x => panic!("{:?}", x),
+    };
+
+    // skip the `push` so that the thread uses the context we have created
+    let idx = client.request(ClientRequest::ThreadCreate("02".into(), Some(ctx)));
+
+    let thread_id = match client.response() {
+        ClientApiRp::Response(x, ClientResponse::ThreadCreated(thread_id)) if x == idx => thread_id,
+        x => panic!("{:?}", x),
+    };
+
+    let mut executed = 0;
+    let started = Instant::now();
+
+    while executed < 12 && started.elapsed() < Duration::from_secs(5) {
+        executed += worker.run();
+        sleep(Duration::from_millis(1));
+    }
+
+    let idx = client.request(ClientRequest::ThreadStatus(thread_id.clone()));
+
+    assert_eq!(
+        client.response(),
+        ClientApiRp::Response(idx, ClientResponse::ThreadStatus(
+            thread_id,
+            Some(ThreadStatus::Exited(Ok(()))),
+        )),
+    );
+
+    tx.send(DaemonRequest::Shutdown).unwrap();
+
+    daemon.join().unwrap();
+}
+
+#[test]
+fn test_api_thread_wait() {
+    let addr: SocketAddr = "127.0.0.1:45101".parse().unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let daemon = spawn(move || DPU::default().run(&rx).unwrap());
+
+    let _adapter = TCPClientAdapter::new(&addr, tx.clone()).unwrap();
+
+    let mut worker = LocalWorker::new(BuiltinWorker::default(), tx.clone());
+    let mut client = ClientTcp::new(&addr).unwrap();
+
+    let ir = LoadIRFile::new(TEST_COUNT);
+    let ir = ir.load().unwrap();
+
+    client.request(ClientRequest::CommandsAdd(ir));
+    client.response();
+
+    let idx = client.request(ClientRequest::ThreadWait("nx".into()));
+
+    assert_eq!(
+        client.response(),
+        ClientApiRp::Response(idx, ClientResponse::ThreadStatus("nx".into(), None)),
+    );
+
+    let idx = client.request(ClientRequest::ThreadCreate("ep".into(), None));
+
+    let thread_id = match client.response() {
+        ClientApiRp::Response(x, ClientResponse::ThreadCreated(thread_id)) if x == idx => thread_id,
+        x => panic!("{:?}", x),
+    };
+
+    let idx = client.request(ClientRequest::ThreadWait(thread_id.clone()));
+
+    let started = Instant::now();
+
+    while started.elapsed() < Duration::from_secs(5) {
+        worker.run();
+        client.chan.rx_loop().unwrap();
+
+        if let Ok(rp) = client.rx.try_recv() {
+            match rp {
+                ClientApiRp::Response(x, ClientResponse::ThreadExited(exit)) => {
+                    assert_eq!(x, idx);
+                    assert_eq!(exit.id, thread_id);
+                    assert_eq!(exit.result, Ok(()));
+                    assert_eq!(exit.ctx.unwrap().get(&"i".into()), Some(ContextValue::Int(3)));
+                }
+                x => panic!("{:?}", x),
+            }
+
+            tx.send(DaemonRequest::Shutdown).unwrap();
+            daemon.join().unwrap();
+
+            return;
+        }
+
+        sleep(Duration::from_millis(1));
+    }
+
+    panic!("thread did not exit");
+}
+
+#[test]
+fn test_api_packet_too_large() {
+    let packet = |len: usize| ClientApiRq::Request(0, ClientRequest::ThreadStatus("a".repeat(len)));
+
+    let overhead = write_json_packet(&packet(0)).unwrap().len() - 2;
+    let limit = u16::MAX as usize - overhead;
+
+    let rq = packet(limit);
+    let written = write_json_packet(&rq).unwrap();
+
+    assert_eq!(written.len(), u16::MAX as usize + 2);
+    assert_eq!(read_json_packet::<ClientApiRq>(&written), Ok((b"".as_ref(), rq)));
+
+    // the length would not fit the prefix, so nothing is written at all
+    assert!(write_json_packet(&packet(limit + 1)).is_err());
+}
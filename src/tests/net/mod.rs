@@ -1 +1,2 @@
-mod tcp;
\ No newline at end of file
+mod tcp;
+mod api;
@@ -15,14 +15,17 @@ use crate::tests::prog::LoadIRFile;
 use crate::daemon::Ass;
 use crate::tests::prog::TEST_ALGO;
 use crate::daemon::DPU;
+use crate::daemon::QueuePolicies;
 use crate::obj::XCmd;
 use crate::obj::XCmdArg;
 use crate::daemon::ThreadState;
+use crate::daemon::ThreadStatus;
+use crate::daemon::Op;
 use crate::obj::XCtxRef;
 use crate::obj::XCtxNs;
 use std::thread::sleep;
 use std::time::Duration;
-use crate::tests::worker::FirstExecutor;
+use crate::tests::worker::{FirstExecutor, users};
 use crate::net::tcp::StreamForwarder;
 use mio::net::TcpStream;
 
@@ -144,6 +147,10 @@ impl WorkerTcp {
                     self.tx.send(ClientBkRq::Result(idx, ret));
                     self.chan.tx_loop().expect("b");
                 }
+                ClientBkRp::Ping(x) => {
+                    self.tx.send(ClientBkRq::Pong(x)).unwrap();
+                    self.chan.tx_loop().expect("c");
+                }
             }
         }
         i
@@ -167,7 +174,7 @@ fn test_client_local() {
 
     //let (tx, rx) = channel::<DaemonRequest>();
 
-    let thread_id = DPU::job_add(
+    let (thread_id, _) = DPU::job_add(
         "ep".into(),
         None,
         &mut state,
@@ -191,6 +198,7 @@ fn test_client_local() {
     let listener = TCPWorkerAdapter::new(
         &addr,
         master_tx.clone(),
+        WORKER_DEADLINE,
     ).unwrap();
 
     let mut w = WorkerTcp::new(&addr).unwrap();
@@ -202,14 +210,17 @@ fn test_client_local() {
             &mut state,
             &mut assignment_queue,
             &mut workers,
+            &mut QueuePolicies::default(),
         );
 
         DPU::process_channel(
             &master_rx,
+            &mut None,
             &mut state,
             &mut assignment_queue,
             &mut workers,
             &mut multi_queue,
+            &mut QueuePolicies::default(),
         );
         w.run();
         sleep(Duration::from_millis(1));
@@ -219,9 +230,9 @@ fn test_client_local() {
         state.threads.get(&thread_id).unwrap().state,
         ThreadState::Queued(
             XCmd::create("07".into(), "list_get".into(), vec![
-                XCmdArg::Ref(XCtxRef(XCtxNs::Curr, "users".into()), Some("foo@bar.com,zeta@beta.org,culinary@sky.net".into())),
+                XCmdArg::Ref(XCtxRef(XCtxNs::Curr, "users".into()), Some(users())),
                 XCmdArg::Ref(XCtxRef(XCtxNs::Curr, "i".into()), Some("0".into())),
-                XCmdArg::Const("user_id".into()),
+                XCmdArg::Ref(XCtxRef(XCtxNs::Curr, "user_id".into()), None),
                 XCmdArg::Const("08".into()),
             ]),
         ),
@@ -229,3 +240,179 @@ fn test_client_local() {
 
 }
 
+
+/// Accept the worker announced on the channel under the given id, the listener drops the
+/// worker once the returned sender goes away.
+fn worker_created(master_rx: &Receiver<DaemonRequest>, id: &str) -> Sender<DaemonWorker> {
+    for _ in 0..100 {
+        match master_rx.try_recv() {
+            Ok(DaemonRequest::WorkerAdd(_, atx)) => {
+                atx.send(DaemonWorker::WorkerCreated(id.into())).unwrap();
+                return atx;
+            }
+            Ok(_) => panic!("expected the worker to be announced"),
+            Err(_) => sleep(Duration::from_millis(10)),
+        }
+    }
+
+    panic!("the worker was not announced");
+}
+
+#[test]
+fn test_worker_deadline() {
+    let (master_tx, master_rx) = channel::<DaemonRequest>();
+
+    let addr: SocketAddr = "127.0.0.1:45001".parse().unwrap();
+
+    let _listener = TCPWorkerAdapter::new(
+        &addr,
+        master_tx.clone(),
+        Duration::from_millis(300),
+    ).unwrap();
+
+    let mut alive = WorkerTcp::new(&addr).unwrap();
+    alive.header();
+    let _alive_tx = worker_created(&master_rx, "alive");
+
+    // the socket stays open, but nothing is read from it
+    let mut silent = WorkerTcp::new(&addr).unwrap();
+    silent.header();
+    let _silent_tx = worker_created(&master_rx, "silent");
+
+    let mut removed = vec![];
+
+    for _ in 0..100 {
+        alive.run();
+
+        while let Ok(x) = master_rx.try_recv() {
+            match x {
+                DaemonRequest::WorkerRemove(wid) => removed.push(wid),
+                _ => panic!("expected the worker to be removed"),
+            }
+        }
+
+        sleep(Duration::from_millis(10));
+    }
+
+    assert_eq!(removed, vec!["silent".to_string()]);
+}
+
+#[test]
+fn test_worker_killed() {
+    let ir = LoadIRFile::new(TEST_ALGO);
+    let ir = ir.load().unwrap();
+
+    let (master_tx, master_rx) = channel::<DaemonRequest>();
+
+    let mut dpu = DPU::default();
+    dpu.get_state_mut().insert_commands(ir.iter());
+
+    let addr: SocketAddr = "127.0.0.1:45002".parse().unwrap();
+
+    let _listener = TCPWorkerAdapter::new(
+        &addr,
+        master_tx.clone(),
+        WORKER_DEADLINE,
+    ).unwrap();
+
+    let mut killed = WorkerTcp::new(&addr).unwrap();
+    killed.header();
+
+    let (thread_id, _) = dpu.thread_start("ep".into(), None);
+
+    // the first job is sent to the worker, which never answers it
+    let mut request = None;
+
+    for _ in 0..100 {
+        dpu.process(&master_rx).unwrap();
+
+        killed.chan.rx_loop().unwrap();
+
+        if let Ok(x) = killed.rx.try_recv() {
+            request = Some(x);
+            break;
+        }
+
+        sleep(Duration::from_millis(1));
+    }
+
+    let idx = match request {
+        Some(ClientBkRp::Request(idx, _)) => idx,
+        x => panic!("{:?}", x),
+    };
+
+    let mut w = WorkerTcp::new(&addr).unwrap();
+    w.header();
+
+    for _ in 0..20 {
+        dpu.process(&master_rx).unwrap();
+        w.run();
+        sleep(Duration::from_millis(1));
+    }
+
+    // the job is only handed to the other worker once the connection is lost
+    assert_eq!(dpu.thread_status(&thread_id), Some(ThreadStatus::Running("ep".into())));
+
+    drop(killed);
+
+    for _ in 0..100 {
+        dpu.process(&master_rx).unwrap();
+        w.run();
+        sleep(Duration::from_millis(1));
+    }
+
+    assert_eq!(
+        dpu.get_state_mut().threads.get(&thread_id).unwrap().state,
+        ThreadState::Queued(
+            XCmd::create("07".into(), "list_get".into(), vec![
+                XCmdArg::Ref(XCtxRef(XCtxNs::Curr, "users".into()), Some(users())),
+                XCmdArg::Ref(XCtxRef(XCtxNs::Curr, "i".into()), Some("0".into())),
+                XCmdArg::Ref(XCtxRef(XCtxNs::Curr, "user_id".into()), None),
+                XCmdArg::Const("08".into()),
+            ]),
+        ),
+    );
+
+    // the killed worker reconnects, and takes the job none of the others can run
+    let mut reconnected = WorkerTcp::new(&addr).unwrap();
+
+    reconnected.tx.send(ClientBkRq::Header(None, vec!["list_get".into()])).unwrap();
+    reconnected.chan.tx_loop().unwrap();
+
+    let mut request = None;
+
+    for _ in 0..100 {
+        dpu.process(&master_rx).unwrap();
+
+        reconnected.chan.rx_loop().unwrap();
+
+        if let Ok(x) = reconnected.rx.try_recv() {
+            request = Some(x);
+            break;
+        }
+
+        sleep(Duration::from_millis(1));
+    }
+
+    match request {
+        Some(ClientBkRp::Request(x, _)) => assert_ne!(x, idx),
+        x => panic!("{:?}", x),
+    };
+
+    assert!(matches!(dpu.get_state_mut().threads.get(&thread_id).unwrap().state, ThreadState::Assigned(..)));
+
+    // it can not answer the request of its previous connection, and is disconnected for trying
+    reconnected.tx.send(ClientBkRq::Result(idx, Ok(vec![Op::ThreadExit]))).unwrap();
+    reconnected.chan.tx_loop().unwrap();
+
+    for _ in 0..100 {
+        dpu.process(&master_rx).unwrap();
+        sleep(Duration::from_millis(1));
+
+        if let ThreadState::Queued(_) = dpu.get_state_mut().threads.get(&thread_id).unwrap().state {
+            break;
+        }
+    }
+
+    assert!(matches!(dpu.get_state_mut().threads.get(&thread_id).unwrap().state, ThreadState::Queued(_)));
+}
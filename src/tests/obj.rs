@@ -1,6 +1,7 @@
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use crate::daemon::*;
     use crate::obj::*;
     use std::collections::HashMap;
 
@@ -8,7 +9,7 @@ mod tests {
         let mut values = HashMap::<ContextIdent, ContextValue>::default();
 
         for (i, c) in "abcdef".chars().enumerate() {
-            values.insert(c.to_string(), i.to_string());
+            values.insert(c.to_string(), i.to_string().into());
         }
 
         Ctx::create(0.to_string(), values)
@@ -41,4 +42,122 @@ mod tests {
 //            Err(InterpolationError::Ref(CtxRef(CtxNs::Curr, "g".into())))
 //        )
 //    }
-}
\ No newline at end of file
+    #[test]
+    fn test_value_json() {
+        let values = vec![
+            ContextValue::Null,
+            ContextValue::from(""),
+            ContextValue::Int(-3),
+            ContextValue::Bytes(vec![0, 255]),
+            ContextValue::List(vec![true.into(), "a".into()]),
+            ContextValue::Map(vec![("k".to_string(), ContextValue::Null)].into_iter().collect()),
+        ];
+
+        let json = serde_json::to_string(&values).unwrap();
+
+        assert_eq!(
+            json,
+            r#"["Null",{"Str":""},{"Int":-3},{"Bytes":[0,255]},{"List":[{"Bool":true},{"Str":"a"}]},{"Map":{"k":"Null"}}]"#,
+        );
+        assert_eq!(serde_json::from_str::<Vec<ContextValue>>(&json).unwrap(), values);
+    }
+
+    #[test]
+    fn test_interpolate_null() {
+        let mut ctx = create_context();
+        ctx.vals.insert("g".into(), ContextValue::Null);
+
+        let cmd = Cmd::create("0".into(), CmdArg::Const("nop".into()), vec![
+            CmdArg::Ref(CtxRef(CtxNs::Curr, "g".into())),
+            CmdArg::Ref(CtxRef(CtxNs::Curr, "h".into())),
+        ]);
+
+        assert_eq!(
+            DPU::interpolate(&State::default(), &cmd, Some(&ctx)),
+            Ok(XCmd::create("0".into(), "nop".into(), vec![
+                XCmdArg::Ref(XCtxRef(XCtxNs::Curr, "g".into()), Some(ContextValue::Null)),
+                XCmdArg::Ref(XCtxRef(XCtxNs::Curr, "h".into()), None),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_interpolate_ref_path() {
+        let mut state = State::default();
+
+        let mut outer = create_context();
+        outer.vals.insert("x".into(), "outer".into());
+        state.insert_context(&outer);
+
+        let mut inner = Ctx::empty("1".into());
+        inner.vals.insert(LOCAL_PAR_CTX.into(), outer.id.clone().into());
+        inner.vals.insert("x".into(), "inner".into());
+        state.insert_context(&inner);
+
+        let mut ctx = Ctx::empty("2".into());
+        ctx.vals.insert(LOCAL_PAR_CTX.into(), inner.id.clone().into());
+
+        let path = |x: &[&str]| CtxNs::Ref(x.iter().map(|x| x.to_string()).collect());
+
+        let cmd = Cmd::create("0".into(), CmdArg::Const("nop".into()), vec![
+            CmdArg::Ref(CtxRef(path(&["^ctx"]), "x".into())),
+            CmdArg::Ref(CtxRef(path(&["^ctx", "^ctx"]), "x".into())),
+        ]);
+
+        assert_eq!(
+            DPU::interpolate(&state, &cmd, Some(&ctx)),
+            Ok(XCmd::create("0".into(), "nop".into(), vec![
+                XCmdArg::Ref(XCtxRef(XCtxNs::Ref("1".into()), "x".into()), Some("inner".into())),
+                XCmdArg::Ref(XCtxRef(XCtxNs::Ref("0".into()), "x".into()), Some("outer".into())),
+            ]))
+        );
+
+        let cmd = Cmd::create("0".into(), CmdArg::Const("nop".into()), vec![
+            CmdArg::Ref(CtxRef(path(&["^ctx", "^ctx", "^ctx"]), "x".into())),
+        ]);
+
+        assert_eq!(
+            DPU::interpolate(&state, &cmd, Some(&ctx)),
+            Err(InterpolationError::Ref(CtxRef(path(&["^ctx", "^ctx"]), "^ctx".into())))
+        );
+
+        ctx.vals.insert("a".into(), "nx".into());
+
+        let cmd = Cmd::create("0".into(), CmdArg::Const("nop".into()), vec![
+            CmdArg::Ref(CtxRef(path(&["a"]), "x".into())),
+        ]);
+
+        assert_eq!(
+            DPU::interpolate(&state, &cmd, Some(&ctx)),
+            Err(InterpolationError::CtxMiss("nx".into()))
+        );
+    }
+
+    #[test]
+    fn test_interpolate_lexical() {
+        let mut state = State::default();
+
+        let outer = create_context();
+        state.insert_context(&outer);
+
+        let mut ctx = Ctx::empty("1".into());
+        ctx.vals.insert(LOCAL_PAR_CTX.into(), outer.id.clone().into());
+        ctx.vals.insert("a".into(), "inner".into());
+        state.insert_context(&ctx);
+
+        let cmd = Cmd::create("0".into(), CmdArg::Const("nop".into()), vec![
+            CmdArg::Ref(CtxRef(CtxNs::Lexical, "a".into())),
+            CmdArg::Ref(CtxRef(CtxNs::Lexical, "b".into())),
+            CmdArg::Ref(CtxRef(CtxNs::Lexical, "g".into())),
+        ]);
+
+        assert_eq!(
+            DPU::interpolate(&state, &cmd, Some(&ctx)),
+            Ok(XCmd::create("0".into(), "nop".into(), vec![
+                XCmdArg::Ref(XCtxRef(XCtxNs::Curr, "a".into()), Some("inner".into())),
+                XCmdArg::Ref(XCtxRef(XCtxNs::Ref("0".into()), "b".into()), Some("1".into())),
+                XCmdArg::Ref(XCtxRef(XCtxNs::Curr, "g".into()), None),
+            ]))
+        );
+    }
+}
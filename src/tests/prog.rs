@@ -155,7 +155,8 @@ fn test_microcode_algo() {
                                               "users".into())),
                            CmdArg::Ref(CtxRef(Curr,
                                               "i".into())),
-                           CmdArg::Const("user_id".into()),
+                           CmdArg::Ref(CtxRef(Curr,
+                                              "user_id".into())),
                            CmdArg::Const("08".into())],
             },
             Cmd {
@@ -210,7 +211,28 @@ fn test_xxx() {
     let r = ctxxref(LocatedSpan::new(b"$asd.zxd "));
     assert_eq!(
         r,
-        Ok((LocatedSpan { offset: 8, line: 1, fragment: [32].as_ref() }, (LocatedSpan { offset: 1, line: 1, fragment: "asd" }, LocatedSpan { offset: 5, line: 1, fragment: "zxd" })))
+        Ok((LocatedSpan { offset: 8, line: 1, fragment: [32].as_ref() }, (vec![LocatedSpan { offset: 1, line: 1, fragment: "asd" }], LocatedSpan { offset: 5, line: 1, fragment: "zxd" })))
+    );
+}
+
+#[test]
+fn test_parent_chain() {
+    let r = ctxxref(LocatedSpan::new(b"$^ctx.^ctx.x "));
+    assert_eq!(
+        r,
+        Ok((LocatedSpan { offset: 12, line: 1, fragment: [32].as_ref() }, (vec![LocatedSpan { offset: 1, line: 1, fragment: "^ctx" }, LocatedSpan { offset: 6, line: 1, fragment: "^ctx" }], LocatedSpan { offset: 11, line: 1, fragment: "x" })))
+    );
+
+    let r = ctxlexref(LocatedSpan::new(b"$*n "));
+    assert_eq!(
+        r,
+        Ok((LocatedSpan { offset: 3, line: 1, fragment: [32].as_ref() }, LocatedSpan { offset: 2, line: 1, fragment: "n" }))
+    );
+
+    let r = ctxref(LocatedSpan::new(b"$^ip "));
+    assert_eq!(
+        r,
+        Ok((LocatedSpan { offset: 4, line: 1, fragment: [32].as_ref() }, LocatedSpan { offset: 1, line: 1, fragment: "^ip" }))
     );
 }
 
@@ -227,7 +249,7 @@ fn test_parent_ref() {
         },
             Cmd {
                 id: "ep".into(),
-                opcode: CmdArg::Ref(CtxRef(CtxNs::Ref("a".into()),
+                opcode: CmdArg::Ref(CtxRef(CtxNs::Ref(vec!["a".into()]),
                                            "b".into())),
                 args: vec![CmdArg::Const("01".into()),
                     CmdArg::Const("a".into())],
@@ -247,7 +269,7 @@ fn test_parent_ref() {
                     CmdArg::Ref(CtxRef(Curr,
                                        "g".into())),
                     CmdArg::Const("asdasdasd".into()),
-                    CmdArg::Ref(CtxRef(CtxNs::Ref("a".into()),
+                    CmdArg::Ref(CtxRef(CtxNs::Ref(vec!["a".into()]),
                                        "b".into())),
                     CmdArg::Const("asd".into())],
             }]),
@@ -264,3 +286,16 @@ fn test_ir_loader() {
         x.load()
     );
 }
+
+#[test]
+fn test_format_error_last_line() {
+    let contents = "ep: push 01\n01:\n";
+    let input = ir_input(contents);
+
+    let err = ir_load::<u32>(ir_file(input)).unwrap_err();
+
+    assert_eq!(
+        format_error(&input, &err).unwrap(),
+        "   1: ep: push 01\n   2: 01:\n      ^==================================\n      OpcodeMissing\n   3: "
+    );
+}
@@ -49,7 +49,7 @@ enum Jobs {
 fn test_multi_queue_a() {
     let mut a = MultiQueue::<String, u32, Jobs>::default();
 
-    let ops = a.job_create(&1, &Jobs::A);
+    let ops = a.job_create(&1, &Jobs::A, 0);
 
     assert_eq!(
         ops,
@@ -68,14 +68,14 @@ fn test_multi_queue_a() {
 fn test_multi_queue_b() {
     let mut a = MultiQueue::<String, u32, Jobs>::default();
 
-    let ops = a.job_create(&1, &Jobs::A);
+    let ops = a.job_create(&1, &Jobs::A, 0);
 
     assert_eq!(
         ops,
         vec![],
     );
 
-    let ops = a.job_create(&2, &Jobs::B);
+    let ops = a.job_create(&2, &Jobs::B, 0);
 
     assert_eq!(
         ops,
@@ -97,21 +97,21 @@ fn test_multi_queue_b() {
 fn test_multi_queue_c() {
     let mut a = MultiQueue::<String, u32, Jobs>::default();
 
-    let ops = a.job_create(&1, &Jobs::A);
+    let ops = a.job_create(&1, &Jobs::A, 0);
 
     assert_eq!(
         ops,
         vec![],
     );
 
-    let ops = a.job_create(&2, &Jobs::B);
+    let ops = a.job_create(&2, &Jobs::B, 0);
 
     assert_eq!(
         ops,
         vec![],
     );
 
-    let ops = a.job_create(&2, &Jobs::C);
+    let ops = a.job_create(&2, &Jobs::C, 0);
 
     assert_eq!(
         ops,
@@ -133,21 +133,21 @@ fn test_multi_queue_c() {
 fn test_multi_queue_d() {
     let mut a = MultiQueue::<String, u32, Jobs>::default();
 
-    let ops = a.job_create(&1, &Jobs::A);
+    let ops = a.job_create(&1, &Jobs::A, 0);
 
     assert_eq!(
         ops,
         vec![],
     );
 
-    let ops = a.job_create(&2, &Jobs::B);
+    let ops = a.job_create(&2, &Jobs::B, 0);
 
     assert_eq!(
         ops,
         vec![],
     );
 
-    let ops = a.job_create(&2, &Jobs::C);
+    let ops = a.job_create(&2, &Jobs::C, 0);
 
     assert_eq!(
         ops,
@@ -179,28 +179,28 @@ fn test_multi_queue_d() {
 fn test_multi_queue_e() {
     let mut a = MultiQueue::<String, u32, Jobs>::default();
 
-    let ops = a.job_create(&1, &Jobs::A);
+    let ops = a.job_create(&1, &Jobs::A, 0);
 
     assert_eq!(
         ops,
         vec![],
     );
 
-    let ops = a.job_create(&2, &Jobs::B);
+    let ops = a.job_create(&2, &Jobs::B, 0);
 
     assert_eq!(
         ops,
         vec![],
     );
 
-    let ops = a.job_create(&2, &Jobs::C);
+    let ops = a.job_create(&2, &Jobs::C, 0);
 
     assert_eq!(
         ops,
         vec![],
     );
 
-    let ops = a.job_create(&2, &Jobs::D);
+    let ops = a.job_create(&2, &Jobs::D, 0);
 
     assert_eq!(
         ops,
@@ -241,7 +241,7 @@ fn test_multi_queue_e() {
 fn test_multi_queue_cancel_job() {
     let mut a = MultiQueue::<String, u32, Jobs>::default();
 
-    let ops = a.job_create(&1, &Jobs::A);
+    let ops = a.job_create(&1, &Jobs::A, 0);
 
     assert_eq!(
         ops,
@@ -261,7 +261,7 @@ fn test_multi_queue_cancel() {
     let mut a = MultiQueue::<String, u32, Jobs>::default();
     let ops2 = a.worker_add("a".to_string(), None, &vec![1, 2, 3]);
 
-    let ops = a.job_create(&1, &Jobs::A);
+    let ops = a.job_create(&1, &Jobs::A, 0);
 
     assert_eq!(
         ops,
@@ -270,7 +270,7 @@ fn test_multi_queue_cancel() {
         ],
     );
 
-    let ops = a.job_create(&1, &Jobs::B);
+    let ops = a.job_create(&1, &Jobs::B, 0);
 
     assert_eq!(
         ops,
@@ -303,3 +303,348 @@ fn test_multi_queue_cancel() {
         ],
     );
 }
+
+/// Assign the jobs on queue 1 to the workers 1000, 2000 and 3000, counting the jobs of each.
+fn distribute(selection: Selection, capacity: Option<usize>, jobs: u32) -> Vec<(u32, usize)> {
+    let mut a = PubSub::<u32, u32, u32>::default();
+
+    a.queues_selections.insert(1, selection);
+
+    for worker in &[1000, 2000, 3000] {
+        a.add(*worker, capacity, &vec![1]);
+    }
+
+    let mut counts = vec![(1000, 0), (2000, 0), (3000, 0)];
+
+    for job in 0..jobs {
+        let worker = a.assign(&1, &job).unwrap();
+
+        counts.iter_mut().find(|(x, _)| *x == worker).unwrap().1 += 1;
+    }
+
+    counts
+}
+
+#[test]
+fn test_select_round_robin() {
+    let mut a = PubSub::<u32, u32, u32>::default();
+
+    a.queues_selections.insert(1, Selection::RoundRobin);
+
+    a.add(1000, None, &vec![1]);
+    a.add(2000, None, &vec![1]);
+    a.add(3000, None, &vec![1]);
+
+    let order: Vec<u32> = (0..6).map(|job| a.assign(&1, &job).unwrap()).collect();
+
+    assert_eq!(order, vec![1000, 2000, 3000, 1000, 2000, 3000]);
+
+    assert_eq!(
+        distribute(Selection::RoundRobin, None, 300),
+        vec![(1000, 100), (2000, 100), (3000, 100)],
+    );
+}
+
+#[test]
+fn test_select_round_robin_full() {
+    let mut a = PubSub::<u32, u32, u32>::default();
+
+    a.queues_selections.insert(1, Selection::RoundRobin);
+
+    a.add(1000, Some(1), &vec![1]);
+    a.add(2000, None, &vec![1]);
+
+    // the full worker is skipped until its job is done
+    assert_eq!(a.assign(&1, &0), Some(1000));
+    assert_eq!(a.assign(&1, &1), Some(2000));
+    assert_eq!(a.assign(&1, &2), Some(2000));
+
+    a.resign(&1, &0);
+
+    assert_eq!(a.assign(&1, &3), Some(1000));
+}
+
+#[test]
+fn test_select_least_loaded() {
+    // far from the capacity, so the spread is down to the selection alone
+    assert_eq!(
+        distribute(Selection::LeastLoaded, Some(100), 30),
+        vec![(1000, 10), (2000, 10), (3000, 10)],
+    );
+
+    let mut a = PubSub::<u32, u32, u32>::default();
+
+    a.add(1000, Some(6), &vec![1]);
+    a.add(2000, Some(2), &vec![1]);
+
+    let order: Vec<u32> = (0..4).map(|job| a.assign(&1, &job).unwrap()).collect();
+
+    // the share of the capacity is what counts, each in turn would have been 2 and 2
+    assert_eq!(order.iter().filter(|x| **x == 1000).count(), 3);
+    assert_eq!(order.iter().filter(|x| **x == 2000).count(), 1);
+
+    a.resign(&1, &order.iter().position(|x| *x == 2000).map(|x| x as u32).unwrap());
+
+    assert_eq!(a.assign(&1, &4), Some(2000));
+}
+
+#[test]
+fn test_select_random() {
+    for (_, count) in distribute(Selection::Random, None, 3000) {
+        assert!(count > 800 && count < 1200, "{}", count);
+    }
+}
+
+#[test]
+fn test_queue_priority() {
+    let mut a = MultiQueue::<String, u32, Jobs>::default();
+
+    a.queue_weight(2, QueueWeight { priority: 1, weight: 1 });
+
+    a.job_create(&1, &Jobs::A, 0);
+    a.job_create(&1, &Jobs::B, 0);
+    a.job_create(&2, &Jobs::C, 0);
+    a.job_create(&2, &Jobs::D, 0);
+
+    let ops = a.worker_add("a".to_string(), Some(3), &vec![1, 2]);
+
+    assert_eq!(
+        ops,
+        vec![
+            Assignment::new(Started, "a".into(), 2, Jobs::C),
+            Assignment::new(Started, "a".into(), 2, Jobs::D),
+            Assignment::new(Started, "a".into(), 1, Jobs::A),
+        ],
+    );
+}
+
+#[test]
+fn test_queue_weights() {
+    let mut a = MultiQueue::<String, u32, u32>::default();
+
+    a.queue_weight(1, QueueWeight { priority: 0, weight: 3 });
+
+    for job in 0..100 {
+        a.job_create(&1, &job, 0);
+        a.job_create(&2, &(job + 100), 0);
+    }
+
+    let mut ops = a.worker_add("a".to_string(), Some(1), &vec![1, 2]);
+    let mut counts = (0, 0);
+
+    // every job is finished right away, so the worker takes one at a time
+    for _ in 0..80 {
+        let job = ops.pop().unwrap();
+
+        match job.queue_key {
+            1 => counts.0 += 1,
+            _ => counts.1 += 1,
+        }
+
+        ops = a.job_finish(&job.queue_key, &job.job_key).into_iter().filter(|x| x.action == Started).collect();
+    }
+
+    assert_eq!(counts, (60, 20));
+}
+
+#[test]
+fn test_worker_remove_skips_full_queues() {
+    let mut a = MultiQueue::<String, u32, Jobs>::default();
+
+    a.queue_weight(1, QueueWeight { priority: 1, weight: 1 });
+
+    a.worker_add("gone".to_string(), Some(2), &vec![1, 2]);
+    a.job_create(&1, &Jobs::B, 0);
+    a.job_create(&2, &Jobs::C, 0);
+
+    a.worker_add("busy".to_string(), Some(1), &vec![1]);
+    a.job_create(&1, &Jobs::A, 0);
+    a.job_create(&1, &Jobs::E, 0);
+
+    a.worker_add("idle".to_string(), Some(1), &vec![2]);
+
+    // nothing is ready for queue 1, which does not hold back the job of queue 2
+    let mut ops = a.worker_remove(&"gone".into());
+
+    ops.sort_by_key(|x| (x.worker_key.clone(), x.job_key.clone()));
+
+    assert_eq!(
+        ops,
+        vec![
+            Assignment::new(Cancelled, "gone".into(), 1, Jobs::B),
+            Assignment::new(Cancelled, "gone".into(), 2, Jobs::C),
+            Assignment::new(Started, "idle".into(), 2, Jobs::C),
+        ],
+    );
+
+    assert_eq!(a.queues.get(&1).unwrap().iter().collect::<Vec<_>>(), vec![&Jobs::E, &Jobs::B]);
+}
+
+#[test]
+fn test_job_create_behind_pending() {
+    let mut a = MultiQueue::<String, u32, Jobs>::default();
+
+    a.worker_add("a".to_string(), Some(1), &vec![1]);
+
+    // a job left pending on the queue, while the worker is free
+    a.queues.entry(1).or_default().push_back(Jobs::A);
+
+    assert_eq!(a.job_create(&1, &Jobs::B, 0), vec![Assignment::new(Started, "a".into(), 1, Jobs::A)]);
+
+    a.job_create(&1, &Jobs::C, 5);
+
+    // the job with the higher priority is next, the other ones keep their order
+    assert_eq!(
+        a.job_finish(&1, &Jobs::A),
+        vec![
+            Assignment::new(Done, "a".into(), 1, Jobs::A),
+            Assignment::new(Started, "a".into(), 1, Jobs::C),
+        ],
+    );
+
+    assert_eq!(a.queues.get(&1).unwrap().iter().collect::<Vec<_>>(), vec![&Jobs::B]);
+}
+
+#[test]
+fn test_queue_credits_unassigned() {
+    let mut a = MultiQueue::<String, u32, Jobs>::default();
+
+    a.worker_add("b".to_string(), Some(1), &vec![1, 2]);
+    a.job_create(&2, &Jobs::B, 0);
+
+    a.worker_add("a".to_string(), Some(1), &vec![1]);
+    a.job_create(&1, &Jobs::A, 0);
+
+    a.job_create(&1, &Jobs::C, 0);
+    a.job_create(&2, &Jobs::D, 0);
+
+    // `a` is busy, so neither queue gets a job out of the removal
+    let ops = a.worker_remove(&"b".into());
+
+    assert_eq!(ops, vec![Assignment::new(Cancelled, "b".into(), 2, Jobs::B)]);
+    assert!(a.queues_credits.values().all(|x| *x == 0), "{:?}", a.queues_credits);
+}
+
+#[test]
+fn test_job_priority() {
+    let mut a = MultiQueue::<String, u32, Jobs>::default();
+
+    a.job_create(&1, &Jobs::A, 0);
+    a.job_create(&1, &Jobs::B, 5);
+    a.job_create(&1, &Jobs::C, 0);
+    a.job_create(&1, &Jobs::D, 5);
+    a.job_create(&1, &Jobs::E, -1);
+
+    let ops = a.worker_add("a".to_string(), Some(2), &vec![1]);
+
+    assert_eq!(
+        ops,
+        vec![
+            Assignment::new(Started, "a".into(), 1, Jobs::B),
+            Assignment::new(Started, "a".into(), 1, Jobs::D),
+        ],
+    );
+
+    // the jobs of a removed worker go back in line by their priority
+    assert_eq!(a.worker_remove(&"a".into()).len(), 2);
+
+    let ops = a.worker_add("b".to_string(), Some(5), &vec![1]);
+    let mut jobs: Vec<Jobs> = ops.into_iter().map(|x| x.job_key).collect();
+
+    // the removed worker had its jobs in no particular order
+    jobs[..2].sort();
+
+    assert_eq!(jobs, vec![Jobs::B, Jobs::D, Jobs::A, Jobs::C, Jobs::E]);
+}
+
+#[test]
+fn test_queue_limit_running() {
+    let mut a = MultiQueue::<String, u32, Jobs>::default();
+
+    a.worker_add("a".to_string(), None, &vec![1]);
+
+    assert_eq!(a.job_create(&1, &Jobs::A, 0).len(), 1);
+    assert_eq!(a.job_create(&1, &Jobs::B, 0).len(), 1);
+
+    // the jobs running before the limit was set count towards it
+    a.queue_limit(1, QueueLimit { concurrency: Some(2), rate: None });
+
+    assert_eq!(a.job_create(&1, &Jobs::C, 0), vec![]);
+
+    assert_eq!(
+        a.job_finish(&1, &Jobs::A),
+        vec![
+            Assignment::new(Done, "a".into(), 1, Jobs::A),
+            Assignment::new(Started, "a".into(), 1, Jobs::C),
+        ],
+    );
+}
+
+#[test]
+fn test_queue_concurrency() {
+    let mut a = MultiQueue::<String, u32, Jobs>::default();
+
+    a.queue_limit(1, QueueLimit { concurrency: Some(2), rate: None });
+
+    a.worker_add("a".to_string(), None, &vec![1]);
+    a.worker_add("b".to_string(), None, &vec![1]);
+
+    assert_eq!(a.job_create(&1, &Jobs::A, 0).len(), 1);
+    assert_eq!(a.job_create(&1, &Jobs::B, 0).len(), 1);
+    assert_eq!(a.job_create(&1, &Jobs::C, 0), vec![]);
+
+    let worker = a.pubsub.jobs_workers.get(&Jobs::A).unwrap().clone();
+
+    assert_eq!(
+        a.job_finish(&1, &Jobs::A),
+        vec![
+            Assignment::new(Done, worker.clone(), 1, Jobs::A),
+            Assignment::new(Started, a.pubsub.jobs_workers.get(&Jobs::C).unwrap().clone(), 1, Jobs::C),
+        ],
+    );
+
+    // the jobs cancelled with their worker free their slots too, the one of `a` goes to `b`
+    let ops = a.worker_remove(&"a".into());
+
+    assert_eq!(ops.iter().map(|x| x.action.clone()).collect::<Vec<_>>(), vec![Cancelled, Started]);
+
+    assert_eq!(a.worker_remove(&"b".into()).len(), 2);
+
+    let ops = a.worker_add("c".to_string(), None, &vec![1]);
+
+    assert_eq!(ops.len(), 2);
+}
+
+#[test]
+fn test_queue_rate() {
+    let mut a = MultiQueue::<String, u32, Jobs>::default();
+
+    a.queue_limit(1, QueueLimit { concurrency: None, rate: Some(2) });
+    a.tick(10_000);
+
+    a.worker_add("a".to_string(), None, &vec![1, 2]);
+
+    assert_eq!(a.job_create(&1, &Jobs::A, 0).len(), 1);
+    assert_eq!(a.job_create(&1, &Jobs::B, 0).len(), 1);
+    assert_eq!(a.job_create(&1, &Jobs::C, 0), vec![]);
+    assert_eq!(a.job_create(&1, &Jobs::D, 0), vec![]);
+
+    // the other queues are not held back
+    assert_eq!(a.job_create(&2, &Jobs::E, 0).len(), 1);
+
+    // finishing the jobs does not make up for the rate
+    a.job_finish(&1, &Jobs::A);
+
+    assert_eq!(a.next_release(), Some(11_000));
+    assert_eq!(a.tick(10_999), vec![]);
+
+    assert_eq!(
+        a.tick(11_000),
+        vec![
+            Assignment::new(Started, "a".into(), 1, Jobs::C),
+            Assignment::new(Started, "a".into(), 1, Jobs::D),
+        ],
+    );
+
+    assert_eq!(a.next_release(), None);
+}
@@ -0,0 +1,289 @@
+use std::fs::{OpenOptions, create_dir, read_to_string, remove_dir, remove_file};
+use std::io::Write;
+use std::path::PathBuf;
+
+use mio_extras::channel::channel;
+
+use crate::builtin::BuiltinWorker;
+use crate::daemon::*;
+use crate::obj::*;
+use crate::wal::*;
+use crate::tests::builtin::{TEST_APPROVAL, TEST_COUNT, TEST_SLEEP};
+use crate::tests::prog::LoadIRFile;
+use crate::worker::LocalWorker;
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("yci-wal-{}-{}.json", name, std::process::id()))
+}
+
+fn cleanup(path: &PathBuf) {
+    let _ = remove_file(path);
+    let _ = remove_file(path.with_extension("log"));
+}
+
+/// Start counting and stop the daemon half way through, without taking a snapshot.
+fn crash_halfway(path: &PathBuf, compact_every: usize) -> ThreadId {
+    let ir = LoadIRFile::new(TEST_COUNT);
+    let ir = ir.load().unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let mut dpu = DPU::open(path, compact_every).unwrap();
+    dpu.get_state_mut().insert_commands(ir.iter());
+
+    let mut worker = LocalWorker::new(BuiltinWorker::default(), tx);
+
+    let (thread_id, _) = dpu.thread_start("ep".into(), None);
+
+    for _ in 0..6 {
+        dpu.process(&rx).unwrap();
+        worker.run();
+    }
+
+    dpu.process(&rx).unwrap();
+
+    assert_eq!(dpu.thread_status(&thread_id), Some(ThreadStatus::Running("03".into())));
+
+    thread_id
+}
+
+fn finish(dpu: &mut DPU, thread_id: &ThreadId) -> ThreadExit {
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let exit = dpu.thread_subscribe(thread_id).unwrap();
+    let mut worker = LocalWorker::new(BuiltinWorker::default(), tx);
+
+    for _ in 0..100 {
+        dpu.process(&rx).unwrap();
+        worker.run();
+    }
+
+    exit.try_recv().unwrap()
+}
+
+#[test]
+fn test_wal_replay() {
+    let path = temp_path("replay");
+    cleanup(&path);
+
+    let thread_id = crash_halfway(&path, WAL_COMPACT_EVERY);
+
+    assert!(path.with_extension("log").metadata().unwrap().len() > 0);
+
+    let mut dpu = DPU::open(&path, WAL_COMPACT_EVERY).unwrap();
+
+    assert_eq!(dpu.thread_status(&thread_id), Some(ThreadStatus::Running("03".into())));
+
+    let exit = finish(&mut dpu, &thread_id);
+
+    assert_eq!(exit.result, Ok(()));
+    assert_eq!(exit.ctx.unwrap().get(&"i".into()), Some(ContextValue::Int(3)));
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_wal_compacted() {
+    let path = temp_path("compacted");
+    cleanup(&path);
+
+    let thread_id = crash_halfway(&path, 2);
+
+    let mut dpu = DPU::open(&path, 2).unwrap();
+
+    assert_eq!(dpu.thread_status(&thread_id), Some(ThreadStatus::Running("03".into())));
+    assert_eq!(finish(&mut dpu, &thread_id).result, Ok(()));
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_wal_torn_entry() {
+    let path = temp_path("torn");
+    cleanup(&path);
+
+    let thread_id = crash_halfway(&path, WAL_COMPACT_EVERY);
+
+    let mut log = OpenOptions::new().append(true).open(path.with_extension("log")).unwrap();
+    log.write_all(b"{\"step\":[").unwrap();
+
+    let dpu = DPU::open(&path, WAL_COMPACT_EVERY).unwrap();
+
+    assert_eq!(dpu.thread_status(&thread_id), Some(ThreadStatus::Running("03".into())));
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_wal_result_applied_once() {
+    let path = temp_path("once");
+    cleanup(&path);
+
+    let thread_id = crash_halfway(&path, WAL_COMPACT_EVERY);
+
+    let mut dpu = DPU::open(&path, WAL_COMPACT_EVERY).unwrap();
+
+    let step = dpu.get_state_mut().threads.get(&thread_id).unwrap().step;
+
+    // the result for the step that was in flight during the crash arrives after the restart
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    tx.send(DaemonRequest::Finished("0".into(), thread_id.clone(), step - 1, "if".into(), Ok(vec![Op::ThreadExit]))).unwrap();
+    dpu.process(&rx).unwrap();
+
+    assert_eq!(dpu.thread_status(&thread_id), Some(ThreadStatus::Running("03".into())));
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_wal_pause() {
+    let path = temp_path("pause");
+    cleanup(&path);
+
+    let ir = LoadIRFile::new(TEST_APPROVAL);
+    let ir = ir.load().unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let mut dpu = DPU::open(&path, WAL_COMPACT_EVERY).unwrap();
+    dpu.get_state_mut().insert_commands(ir.iter());
+
+    let mut worker = LocalWorker::new(BuiltinWorker::default(), tx);
+
+    let (thread_id, _) = dpu.thread_start("ep".into(), None);
+
+    for _ in 0..10 {
+        dpu.process(&rx).unwrap();
+        worker.run();
+    }
+
+    let pause_id = match dpu.thread_status(&thread_id) {
+        Some(ThreadStatus::Paused(x)) => x,
+        x => panic!("{:?}", x),
+    };
+
+    drop(dpu);
+
+    let mut dpu = DPU::open(&path, WAL_COMPACT_EVERY).unwrap();
+
+    assert!(dpu.thread_unpause(&pause_id, Default::default()));
+
+    assert_eq!(finish(&mut dpu, &thread_id).result, Ok(()));
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_wal_sleep() {
+    let path = temp_path("sleep");
+    cleanup(&path);
+
+    let ir = LoadIRFile::new(TEST_SLEEP);
+    let ir = ir.load().unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let mut dpu = DPU::open(&path, WAL_COMPACT_EVERY).unwrap();
+    dpu.get_state_mut().insert_commands(ir.iter());
+
+    let mut worker = LocalWorker::new(BuiltinWorker::default(), tx);
+
+    let (thread_id, _) = dpu.thread_start("ep".into(), None);
+
+    for _ in 0..10 {
+        dpu.process(&rx).unwrap();
+        worker.run();
+    }
+
+    let deadline = match dpu.thread_status(&thread_id) {
+        Some(ThreadStatus::Sleeping(x)) => x,
+        x => panic!("{:?}", x),
+    };
+
+    drop(dpu);
+
+    let mut dpu = DPU::open(&path, WAL_COMPACT_EVERY).unwrap();
+
+    assert_eq!(dpu.next_wake(), Some(deadline));
+    assert_eq!(dpu.wake(deadline), 1);
+
+    assert_eq!(finish(&mut dpu, &thread_id).result, Ok(()));
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_wal_write_failed() {
+    let path = temp_path("failed");
+    cleanup(&path);
+
+    let ir = LoadIRFile::new(TEST_COUNT);
+    let ir = ir.load().unwrap();
+
+    let (_tx, rx) = channel::<DaemonRequest>();
+
+    // the log is compacted after every entry
+    let mut dpu = DPU::open(&path, 1).unwrap();
+    dpu.get_state_mut().insert_commands(ir.iter());
+
+    // the snapshot can not be written in place of a directory
+    let tmp_path = path.with_extension("tmp");
+    let _ = remove_dir(&tmp_path);
+    create_dir(&tmp_path).unwrap();
+
+    dpu.thread_start("ep".into(), None);
+
+    assert!(dpu.process(&rx).is_err());
+    assert!(dpu.process(&rx).is_err());
+
+    remove_dir(&tmp_path).unwrap();
+    cleanup(&path);
+}
+
+#[test]
+fn test_wal_worker_removed() {
+    let path = temp_path("worker-removed");
+    cleanup(&path);
+
+    let ir = LoadIRFile::new(TEST_COUNT);
+    let ir = ir.load().unwrap();
+
+    let (tx, rx) = channel::<DaemonRequest>();
+
+    let mut dpu = DPU::open(&path, WAL_COMPACT_EVERY).unwrap();
+    dpu.get_state_mut().insert_commands(ir.iter());
+
+    let (wtx, wrx) = channel::<DaemonWorker>();
+    tx.send(DaemonRequest::WorkerAdd(WorkerInfo(None, vec!["push".into()]), wtx)).unwrap();
+
+    let (thread_id, _) = dpu.thread_start("ep".into(), None);
+
+    dpu.process(&rx).unwrap();
+
+    let worker_id = match wrx.try_recv() {
+        Ok(DaemonWorker::WorkerCreated(x)) => x,
+        x => panic!("{:?}", x),
+    };
+
+    assert!(matches!(wrx.try_recv(), Ok(DaemonWorker::JobAssigned(..))));
+
+    let logged = read_to_string(path.with_extension("log")).unwrap().lines().count();
+
+    tx.send(DaemonRequest::WorkerRemove(worker_id)).unwrap();
+    dpu.process(&rx).unwrap();
+
+    // the job is back on its queue, and so is the thread in the log
+    let log = read_to_string(path.with_extension("log")).unwrap();
+
+    assert_eq!(log.lines().count(), logged + 1);
+
+    let entry: WalEntry = serde_json::from_str(log.lines().last().unwrap()).unwrap();
+
+    match entry.threads.as_slice() {
+        [(x, Some(thread))] if x == &thread_id => assert!(matches!(thread.state, ThreadState::Queued(_))),
+        x => panic!("{:?}", x),
+    }
+
+    cleanup(&path);
+}
@@ -12,6 +12,22 @@ struct W1 {
     wid: Option<WorkerId>,
 }
 
+pub(crate) fn users() -> ContextValue {
+    ContextValue::List(vec![
+        "foo@bar.com".into(),
+        "zeta@beta.org".into(),
+        "culinary@sky.net".into(),
+    ])
+}
+
+fn as_int(val: &ContextValue) -> Option<i64> {
+    match val {
+        ContextValue::Int(x) => Some(*x),
+        ContextValue::Str(x) => x.parse::<i64>().ok(),
+        _ => None,
+    }
+}
+
 pub trait FirstExecutor {
     fn exec(&mut self, command: &XCmd) -> WorkerResult {
         let nip = || {
@@ -65,7 +81,7 @@ pub trait FirstExecutor {
                 Ok(
                     vec![
                         var_name.set(
-                            RValueLocal::Const("".into()),
+                            RValueLocal::Const(ContextValue::List(vec![])),
                         ),
                         Op::LocalSet(
                             LOCAL_NIP.into(),
@@ -87,11 +103,13 @@ pub trait FirstExecutor {
                     WorkerErr::Default(OpErrReason::InvalidArg(0))
                 )?;
 
-                let cnt = var_val.split(",").count();
+                let cnt = var_val.as_list().ok_or(
+                    WorkerErr::Default(OpErrReason::InvalidArg(0))
+                )?.len() as i64;
                 let next_ip = nip()?;
                 Ok(
                     vec![
-                        var_var.set(RValueLocal::Const(cnt.to_string())),
+                        var_var.set(RValueLocal::Const(cnt.into())),
                         Op::LocalSet(
                             LOCAL_NIP.into(),
                             RValue::Local(RValueLocal::Const(next_ip)),
@@ -111,7 +129,7 @@ pub trait FirstExecutor {
 
                 Ok(
                     vec![
-                        var_name.set(RValueLocal::Const("foo@bar.com,zeta@beta.org,culinary@sky.net".into())),
+                        var_name.set(RValueLocal::Const(users())),
                         Op::LocalSet(
                             LOCAL_NIP.into(),
                             RValue::Local(RValueLocal::Const(next_ip)),
@@ -176,8 +194,8 @@ pub trait FirstExecutor {
                     || WorkerErr::Default(OpErrReason::InvalidArg(0))
                 ))?;
 
-                let a = a.parse::<u128>().map_err(
-                    |_| WorkerErr::Default(OpErrReason::InvalidArg(0))
+                let a = as_int(&a).ok_or(
+                    WorkerErr::Default(OpErrReason::InvalidArg(0))
                 )?;
 
                 let op = iter.next().ok_or(
@@ -192,8 +210,8 @@ pub trait FirstExecutor {
                     || WorkerErr::Default(OpErrReason::InvalidArg(2))
                 ))?;
 
-                let b = b.parse::<u128>().map_err(
-                    |_| WorkerErr::Default(OpErrReason::InvalidArg(2))
+                let b = as_int(&b).ok_or(
+                    WorkerErr::Default(OpErrReason::InvalidArg(2))
                 )?;
 
                 let ctxref = iter.next().ok_or(
@@ -202,20 +220,20 @@ pub trait FirstExecutor {
                     || WorkerErr::Default(OpErrReason::InvalidArg(3))
                 )?;
 
-                let cmp_fn: fn(u128, u128) -> bool = match op.as_ref() {
+                let cmp_fn: fn(i64, i64) -> bool = match op.as_str().unwrap_or("") {
                     "<" => |a, b| a < b,
                     ">" => |a, b| a > b,
                     "=" => |a, b| a > b,
                     _ => return Err(WorkerErr::Default(OpErrReason::InvalidArg(1)))
                 };
 
-                let res = cmp_fn(a, b).to_string();
+                let res = cmp_fn(a, b);
 
                 let next_ip = nip()?;
 
                 Ok(
                     vec![
-                        ctxref.set(RValueLocal::Const(res)),
+                        ctxref.set(RValueLocal::Const(res.into())),
                         Op::LocalSet(
                             LOCAL_NIP.into(),
                             RValue::Local(RValueLocal::Const(next_ip)),
@@ -231,8 +249,12 @@ pub trait FirstExecutor {
                     || WorkerErr::Default(OpErrReason::InvalidArg(0))
                 ))?;
 
-                let a = a.parse::<bool>().map_err(
-                    |_| WorkerErr::Default(OpErrReason::InvalidArg(0))
+                let a = match a {
+                    ContextValue::Bool(x) => Some(x),
+                    ContextValue::Str(x) => x.parse::<bool>().ok(),
+                    _ => None,
+                }.ok_or(
+                    WorkerErr::Default(OpErrReason::InvalidArg(0))
                 )?;
 
                 let b = iter.next().ok_or(
@@ -277,7 +299,7 @@ impl Worker for W1 {
         None
     }
 
-    fn queues(&self) -> Vec<ContextValue> {
+    fn queues(&self) -> Vec<CommandId> {
         vec![
             "push".into(),
             "list_create".into(),
@@ -332,7 +354,7 @@ fn test_worker_a() {
 
     let (tx, rx) = channel::<DaemonRequest>();
 
-    let thread_id = DPU::job_add(
+    let (thread_id, _) = DPU::job_add(
         "ep".into(),
         None,
         &mut state,
@@ -366,14 +388,17 @@ fn test_worker_a() {
             &mut state,
             &mut assignment_queue,
             &mut workers,
+            &mut QueuePolicies::default(),
         );
 
         DPU::process_channel(
             &rx,
+            &mut None,
             &mut state,
             &mut assignment_queue,
             &mut workers,
             &mut multi_queue,
+            &mut QueuePolicies::default(),
         );
         for i in 0..100 {
             wo.run();
@@ -384,9 +409,9 @@ fn test_worker_a() {
         state.threads.get(&thread_id).unwrap().state,
         ThreadState::Queued(
             XCmd::create("07".into(), "list_get".into(), vec![
-                XCmdArg::Ref(XCtxRef(XCtxNs::Curr, "users".into()), Some("foo@bar.com,zeta@beta.org,culinary@sky.net".into())),
+                XCmdArg::Ref(XCtxRef(XCtxNs::Curr, "users".into()), Some(users())),
                 XCmdArg::Ref(XCtxRef(XCtxNs::Curr, "i".into()), Some("0".into())),
-                XCmdArg::Const("user_id".into()),
+                XCmdArg::Ref(XCtxRef(XCtxNs::Curr, "user_id".into()), None),
                 XCmdArg::Const("08".into()),
             ]),
         ),
@@ -0,0 +1,143 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use serde_derive::{Serialize, Deserialize};
+
+use crate::daemon::*;
+use crate::obj::*;
+
+pub static WAL_COMPACT_EVERY: usize = 1000;
+
+/// The records changed by a single request, as they were right after it had been processed.
+///
+/// Entries hold the resulting records rather than the `Op`s themselves: replaying the ops would
+/// generate new identifiers for the contexts and the threads they create.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WalEntry {
+    pub(crate) commands: Vec<Cmd>,
+    /// `None` if the record had been removed
+    pub(crate) contexts: Vec<(ContextId, Option<Ctx>)>,
+    pub(crate) threads: Vec<(ThreadId, Option<Thread>)>,
+    #[serde(default)]
+    pub(crate) pauses: Vec<(PauseId, Option<Pause>)>,
+}
+
+/// A snapshot of the `State` followed by a log of everything that changed since it was taken.
+///
+/// Every entry is a complete copy of the records it touches, so replaying it more than once is
+/// harmless. A crash between the snapshot and the truncation of the log is then recovered from by
+/// replaying the old log on top of the new snapshot.
+pub struct Wal {
+    path: PathBuf,
+    log: File,
+    entries: usize,
+    compact_every: usize,
+    /// the error a write had failed with, nothing is written after it
+    failed: Option<io::Error>,
+}
+
+fn log_path(path: &Path) -> PathBuf {
+    path.with_extension("log")
+}
+
+impl Wal {
+    /// Load the snapshot at `path`, replay the log next to it and compact the two.
+    pub fn open<P: AsRef<Path>>(path: P, compact_every: usize) -> io::Result<(State, Wal)> {
+        let path = path.as_ref().to_path_buf();
+
+        let mut state = if path.exists() {
+            State::load(&path)?
+        } else {
+            State::default()
+        };
+
+        let log = OpenOptions::new().read(true).append(true).create(true).open(log_path(&path))?;
+
+        for entry in Wal::read(&log)? {
+            state.apply(entry);
+        }
+
+        let mut wal = Wal {
+            path,
+            log,
+            entries: 0,
+            compact_every,
+            failed: None,
+        };
+
+        // also gets rid of the entry torn by the crash, if any
+        wal.compact(&state)?;
+
+        Ok((state, wal))
+    }
+
+    fn read(log: &File) -> io::Result<Vec<WalEntry>> {
+        let lines = BufReader::new(log).lines().collect::<io::Result<Vec<String>>>()?;
+
+        let mut ret = Vec::<WalEntry>::with_capacity(lines.len());
+
+        for (idx, line) in lines.iter().enumerate() {
+            match serde_json::from_str(line) {
+                Ok(x) => ret.push(x),
+                // the last entry may have been torn by the crash, it was never acknowledged
+                Err(_) if idx + 1 == lines.len() => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// Append the entry, compacting the log if it is due.
+    ///
+    /// Once a write has failed the following entries are dropped, as the log would not be replayed
+    /// into a consistent state with some of them missing.
+    pub fn append(&mut self, entry: &WalEntry, state: &State) -> io::Result<()> {
+        if self.failed.is_some() {
+            return Err(io::Error::new(io::ErrorKind::Other, "an earlier write to the log had failed"));
+        }
+
+        let res = self.write(entry, state);
+
+        if let Err(err) = &res {
+            self.failed = Some(io::Error::new(err.kind(), err.to_string()));
+        }
+
+        res
+    }
+
+    /// The error a write had failed with, if any; the daemon can not go on after it.
+    pub fn failure(&self) -> Option<io::Error> {
+        self.failed.as_ref().map(|x| io::Error::new(x.kind(), x.to_string()))
+    }
+
+    fn write(&mut self, entry: &WalEntry, state: &State) -> io::Result<()> {
+        let mut buf = serde_json::to_vec(entry)?;
+        buf.push(b'\n');
+
+        self.log.write_all(&buf)?;
+        self.log.sync_data()?;
+
+        self.entries += 1;
+
+        if self.entries >= self.compact_every {
+            self.compact(state)?;
+        }
+
+        Ok(())
+    }
+
+    /// Take a snapshot of the state and truncate the log.
+    pub fn compact(&mut self, state: &State) -> io::Result<()> {
+        state.save(&self.path)?;
+
+        self.log.set_len(0)?;
+        self.log.sync_all()?;
+
+        self.entries = 0;
+
+        Ok(())
+    }
+}
@@ -2,9 +2,9 @@
 
 use crate::obj::XCmd;
 use crate::daemon::WorkerResult;
-use crate::daemon::DaemonRequest;
+use crate::daemon::{DaemonRequest, DaemonWorker, WorkerInfo};
 use crate::obj::*;
-use mio_extras::channel::Sender;
+use mio_extras::channel::{Sender, Receiver, channel};
 
 #[derive(Clone)]
 pub struct WorkerReplier {
@@ -54,3 +54,54 @@ pub trait Worker {
     /// Callback would require a mutable reference to the daemon itself
     fn put(&mut self, command: &XCmd, result_cb: WorkerReplier);
 }
+
+/// Drives a `Worker` from within the daemon's process, translating `DaemonWorker` messages
+/// into `Worker::put` calls.
+pub struct LocalWorker<W: Worker> {
+    worker: W,
+    id: Option<WorkerId>,
+    rx: Receiver<DaemonWorker>,
+    sender: Sender<DaemonRequest>,
+}
+
+impl<W: Worker> LocalWorker<W> {
+    /// Announce the worker to the daemon listening on `sender`.
+    pub fn new(worker: W, sender: Sender<DaemonRequest>) -> Self {
+        let (tx, rx) = channel::<DaemonWorker>();
+
+        sender.send(DaemonRequest::WorkerAdd(WorkerInfo(worker.capacity(), worker.queues()), tx)).unwrap();
+
+        LocalWorker {
+            worker,
+            id: None,
+            rx,
+            sender,
+        }
+    }
+
+    pub fn id(&self) -> Option<WorkerId> {
+        self.id.clone()
+    }
+
+    /// Execute every job that had been assigned so far, returns the number of jobs executed.
+    pub fn run(&mut self) -> usize {
+        let mut executed = 0;
+
+        while let Ok(x) = self.rx.try_recv() {
+            match x {
+                DaemonWorker::WorkerCreated(wid) => {
+                    self.id = Some(wid);
+                }
+                DaemonWorker::JobAssigned(tid, sid, cid, cmd) => {
+                    let wid = self.id.clone().expect("job assigned before the worker was created");
+
+                    self.worker.put(&cmd, WorkerReplier::new(wid, cid, tid, sid, self.sender.clone()));
+
+                    executed += 1;
+                }
+            }
+        }
+
+        executed
+    }
+}